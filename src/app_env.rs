@@ -1,21 +1,113 @@
-use std::{collections::HashMap, env, time::SystemTime};
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use jiff::civil::Time;
 
-use crate::app_error::AppError;
+use crate::{
+    C, S,
+    app_error::AppError,
+    journald::JOURNALD_SOCKET,
+    ws::{Proxy, ProxyConfig, TlsConfig},
+};
 
 type EnvHashMap = HashMap<String, String>;
 
+/// The .env files, the first that exists is loaded
+const ENV_FILES: [&str; 2] = ["/app_env/.env", ".env"];
+
+/// WS reconnect backoff, the initial, and maximum, delay ceiling, and how long a connection must be open before the backoff is reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackoffConfig {
+    pub initial: Duration,
+    pub max: Duration,
+    pub reset_after: Duration,
+}
+
+/// Maximum duration of each step of opening the WS connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutConfig {
+    /// The whole token request, including the response body
+    pub token: Duration,
+    /// The TCP connect, via the proxy if set, and the TLS handshake
+    pub connect: Duration,
+    /// The WS upgrade request and response
+    pub upgrade: Duration,
+}
+
+/// Client side WS heartbeat, and the server ping auto close, a zero interval, or auto close, disables that check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// How often the client sends a ping
+    pub interval: Option<Duration>,
+    /// Consecutive unanswered pings before the connection is closed
+    pub missed: u32,
+    /// Close the connection if neither a ping, nor a pong, is received from the server within this window
+    pub auto_close: Option<Duration>,
+}
+
+/// A WS server, and the token server which issues its access tokens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub ws_address: String,
+    pub token_address: String,
+}
+
+/// How the access token is sent with the WS upgrade request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WsAuth {
+    /// Appended to the WS address path, so it may appear in proxy and server access logs
+    #[default]
+    Path,
+    /// As an `Authorization: Bearer` header
+    Bearer,
+    /// As the `Sec-WebSocket-Protocol` header, for servers which can't read custom headers on the upgrade request
+    Protocol,
+}
+
+/// The WS api key, password, and endpoints, without which the daemon runs offline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsConfig {
+    pub apikey: String,
+    pub auth: WsAuth,
+    pub endpoints: Vec<Endpoint>,
+    /// Not required when authenticating with a client certificate
+    pub password: Option<String>,
+}
+
+/// Responses queued whilst the WS is disconnected, a zero size disables the outbox, and it is only persisted if a path is given
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxConfig {
+    pub size: usize,
+    pub path: Option<PathBuf>,
+}
+
+/// How often the primary endpoint is probed whilst connected to a standby, and how long it must stay healthy before failing back to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailoverConfig {
+    pub probe_interval: Duration,
+    pub failback_after: Duration,
+}
+
 #[derive(Debug, Clone)]
 pub struct AppEnv {
+    pub backoff: BackoffConfig,
+    pub failover: FailoverConfig,
+    pub heartbeat: HeartbeatConfig,
     pub log_level: tracing::Level,
+    pub log_journald: Option<PathBuf>,
+    pub outbox: OutboxConfig,
+    pub proxy: ProxyConfig,
     pub start_time: SystemTime,
-    pub ws_address: String,
-    pub ws_apikey: String,
-    pub ws_password: String,
+    pub timeouts: TimeoutConfig,
+    pub tls: TlsConfig,
     pub time_on: Time,
     pub time_off: Time,
-    pub ws_token_address: String,
+    pub ws: Option<WsConfig>,
+    pub ws_token_ttl: Duration,
 }
 
 impl AppEnv {
@@ -42,27 +134,226 @@ impl AppEnv {
         }
     }
 
-    fn parse_time(key: &str, map: &EnvHashMap) -> Time {
-        if let Ok(value) = Self::parse_string(key, map) {
-            let hour = value[0..2]
-                .parse::<u8>()
-                .ok()
-                .and_then(|v| i8::try_from(v).ok());
-
-            let minute = value[2..]
-                .parse::<u8>()
-                .ok()
-                .and_then(|v| i8::try_from(v).ok());
-
-            if let Some(hour) = hour
-                && let Some(minute) = minute
-                && hour < 24
-                && minute < 59
-            {
-                return Time::constant(hour, minute, 0, 0);
+    /// Parse a number of milliseconds, else the default
+    fn parse_millis(key: &str, map: &EnvHashMap, default: u64) -> Duration {
+        Duration::from_millis(
+            map.get(key)
+                .and_then(|i| i.parse::<u64>().ok())
+                .unwrap_or(default),
+        )
+    }
+
+    /// Backoff config, max is at least the initial delay, which is at least 1ms
+    fn parse_backoff(map: &EnvHashMap) -> BackoffConfig {
+        let initial =
+            Self::parse_millis("WS_BACKOFF_INITIAL_MS", map, 1_000).max(Duration::from_millis(1));
+        BackoffConfig {
+            initial,
+            max: Self::parse_millis("WS_BACKOFF_MAX_MS", map, 60_000).max(initial),
+            reset_after: Self::parse_millis("WS_BACKOFF_RESET_MS", map, 30_000),
+        }
+    }
+
+    /// Timeouts are at least 1ms, as a zero timeout would never connect
+    fn parse_timeouts(map: &EnvHashMap) -> TimeoutConfig {
+        let timeout =
+            |key: &str| Self::parse_millis(key, map, 10_000).max(Duration::from_millis(1));
+        TimeoutConfig {
+            token: timeout("WS_TOKEN_TIMEOUT_MS"),
+            connect: timeout("WS_CONNECT_TIMEOUT_MS"),
+            upgrade: timeout("WS_UPGRADE_TIMEOUT_MS"),
+        }
+    }
+
+    /// Comma separated WS_ADDRESS and WS_TOKEN_ADDRESS, paired in order, the first pair is the primary endpoint
+    fn parse_endpoints(map: &EnvHashMap) -> Result<Vec<Endpoint>, AppError> {
+        let split = |key: &str| {
+            Self::parse_string(key, map).map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|i| !i.is_empty())
+                    .map(ToOwned::to_owned)
+                    .collect::<Vec<_>>()
+            })
+        };
+        let (ws_addresses, token_addresses) = (split("WS_ADDRESS")?, split("WS_TOKEN_ADDRESS")?);
+        if ws_addresses.is_empty() {
+            return Err(AppError::MissingEnv(S!("WS_ADDRESS")));
+        }
+        if ws_addresses.len() != token_addresses.len() {
+            return Err(AppError::InvalidEndpoints(format!(
+                "{} WS_ADDRESS, but {} WS_TOKEN_ADDRESS, each WS server needs its own token server",
+                ws_addresses.len(),
+                token_addresses.len()
+            )));
+        }
+        Ok(ws_addresses
+            .into_iter()
+            .zip(token_addresses)
+            .map(|(ws_address, token_address)| Endpoint {
+                ws_address,
+                token_address,
+            })
+            .collect())
+    }
+
+    /// The primary is probed at most every 1ms
+    fn parse_failover(map: &EnvHashMap) -> FailoverConfig {
+        FailoverConfig {
+            probe_interval: Self::parse_millis("WS_PROBE_INTERVAL_MS", map, 30_000)
+                .max(Duration::from_millis(1)),
+            failback_after: Self::parse_millis("WS_FAILBACK_MS", map, 300_000),
+        }
+    }
+
+    /// Heartbeat config, at least one missed pong is needed to close the connection. The heartbeat, and auto close, can't
+    /// both be disabled, as a connection which silently stops responding would then never be closed
+    fn parse_heartbeat(map: &EnvHashMap) -> Result<HeartbeatConfig, AppError> {
+        let enabled = |duration: Duration| (!duration.is_zero()).then_some(duration);
+        let heartbeat = HeartbeatConfig {
+            interval: enabled(Self::parse_millis("WS_PING_INTERVAL_MS", map, 15_000)),
+            missed: map
+                .get("WS_PING_MISSED")
+                .and_then(|i| i.parse::<u32>().ok())
+                .unwrap_or(3)
+                .max(1),
+            auto_close: enabled(Self::parse_millis("WS_AUTOCLOSE_MS", map, 40_000)),
+        };
+        if heartbeat.interval.is_none() && heartbeat.auto_close.is_none() {
+            return Err(AppError::InvalidHeartbeat);
+        }
+        Ok(heartbeat)
+    }
+
+    /// Outbox config, persisted to WS_OUTBOX_FILE if set
+    fn parse_outbox(map: &EnvHashMap) -> OutboxConfig {
+        OutboxConfig {
+            size: map
+                .get("WS_OUTBOX_SIZE")
+                .and_then(|i| i.parse::<usize>().ok())
+                .unwrap_or(100),
+            path: Self::parse_first(&["WS_OUTBOX_FILE"], map).map(PathBuf::from),
+        }
+    }
+
+    /// The first of the given keys that is set, and not empty
+    fn parse_first<'a>(keys: &[&str], map: &'a EnvHashMap) -> Option<&'a str> {
+        keys.iter()
+            .filter_map(|key| map.get(*key))
+            .map(|i| i.trim())
+            .find(|i| !i.is_empty())
+    }
+
+    /// Proxy for both the token request and the WS connection, WS_PROXY takes precedence over the standard envs
+    fn parse_proxy(map: &EnvHashMap) -> Result<ProxyConfig, AppError> {
+        Ok(ProxyConfig {
+            proxy: Self::parse_first(
+                &[
+                    "WS_PROXY",
+                    "HTTPS_PROXY",
+                    "https_proxy",
+                    "ALL_PROXY",
+                    "all_proxy",
+                ],
+                map,
+            )
+            .map(Proxy::parse)
+            .transpose()?,
+            no_proxy: Self::parse_first(&["NO_PROXY", "no_proxy"], map)
+                .map(ProxyConfig::parse_no_proxy)
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Extra CA bundle, client certificate and key, and SPKI pins, the certificate and key must be set together
+    fn parse_tls(map: &EnvHashMap) -> Result<TlsConfig, AppError> {
+        let path = |key: &str| Self::parse_first(&[key], map).map(PathBuf::from);
+        let client_cert = match (path("WS_CLIENT_CERT"), path("WS_CLIENT_KEY")) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => {
+                return Err(AppError::Tls(S!(
+                    "WS_CLIENT_CERT and WS_CLIENT_KEY must both be set"
+                )));
             }
+        };
+        Ok(TlsConfig {
+            ca_bundle: path("WS_CA_BUNDLE"),
+            client_cert,
+            spki_pins: TlsConfig::parse_pins(
+                Self::parse_first(&["WS_SPKI_PINS"], map).unwrap_or_default(),
+            )?,
+        })
+    }
+
+    /// The password is only optional if the device authenticates with a client certificate
+    fn parse_password(map: &EnvHashMap, tls: &TlsConfig) -> Result<Option<String>, AppError> {
+        if tls.client_cert.is_some() {
+            Ok(map.get("WS_PASSWORD").cloned())
+        } else {
+            Self::parse_string("WS_PASSWORD", map).map(Some)
+        }
+    }
+
+    /// The WS subsystem is optional, if none of its required envs are set the daemon runs offline, with only the schedule and
+    /// local control, but a partial config is an error
+    fn parse_ws(map: &EnvHashMap, tls: &TlsConfig) -> Result<Option<WsConfig>, AppError> {
+        if !["WS_ADDRESS", "WS_APIKEY", "WS_PASSWORD", "WS_TOKEN_ADDRESS"]
+            .iter()
+            .any(|key| map.get(*key).is_some_and(|i| !i.trim().is_empty()))
+        {
+            return Ok(None);
+        }
+        Ok(Some(WsConfig {
+            apikey: Self::parse_string("WS_APIKEY", map)?,
+            auth: Self::parse_auth(map)?,
+            endpoints: Self::parse_endpoints(map)?,
+            password: Self::parse_password(map, tls)?,
+        }))
+    }
+
+    /// How the token is sent on the WS upgrade, defaults to the path
+    fn parse_auth(map: &EnvHashMap) -> Result<WsAuth, AppError> {
+        let Some(auth) = Self::parse_first(&["WS_AUTH"], map).map(str::to_lowercase) else {
+            return Ok(WsAuth::default());
+        };
+        match auth.as_str() {
+            "path" => Ok(WsAuth::Path),
+            "bearer" => Ok(WsAuth::Bearer),
+            "protocol" => Ok(WsAuth::Protocol),
+            _ => Err(AppError::InvalidAuth(auth)),
+        }
+    }
+
+    /// Journald socket path, if LOG_JOURNALD is true, or a socket path is given via LOG_JOURNALD_SOCKET
+    fn parse_journald(map: &EnvHashMap) -> Option<PathBuf> {
+        map.get("LOG_JOURNALD_SOCKET")
+            .map(PathBuf::from)
+            .or_else(|| {
+                Self::parse_boolean("LOG_JOURNALD", map).then(|| PathBuf::from(JOURNALD_SOCKET))
+            })
+    }
+
+    /// Parse a HHMM time, error if missing, or invalid
+    fn try_parse_time(key: &str, map: &EnvHashMap) -> Result<Time, AppError> {
+        let value = Self::parse_string(key, map)?;
+        let invalid = || AppError::InvalidTime(key.into(), C!(value));
+        if value.len() != 4 || !value.bytes().all(|i| i.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let hour = value[0..2].parse::<i8>().map_err(|_| invalid())?;
+        let minute = value[2..].parse::<i8>().map_err(|_| invalid())?;
+        if hour < 24 && minute < 60 {
+            Ok(Time::constant(hour, minute, 0, 0))
+        } else {
+            Err(invalid())
         }
-        Time::constant(12, 0, 0, 0)
+    }
+
+    /// Parse a HHMM time, else 12:00
+    fn parse_time(key: &str, map: &EnvHashMap) -> Time {
+        Self::try_parse_time(key, map).unwrap_or(Time::constant(12, 0, 0, 0))
     }
 
     /// Load, and parse .env file, return `AppEnv`
@@ -71,39 +362,87 @@ impl AppEnv {
             .map(|i| (i.0, i.1))
             .collect::<HashMap<String, String>>();
 
+        let tls = Self::parse_tls(&env_map)?;
+        let ws = Self::parse_ws(&env_map, &tls)?;
         Ok(Self {
+            backoff: Self::parse_backoff(&env_map),
+            failover: Self::parse_failover(&env_map),
+            heartbeat: Self::parse_heartbeat(&env_map)?,
             log_level: Self::parse_log(&env_map),
+            log_journald: Self::parse_journald(&env_map),
+            outbox: Self::parse_outbox(&env_map),
+            proxy: Self::parse_proxy(&env_map)?,
             start_time: SystemTime::now(),
+            timeouts: Self::parse_timeouts(&env_map),
+            tls,
             time_off: Self::parse_time("TIME_OFF", &env_map),
             time_on: Self::parse_time("TIME_ON", &env_map),
-            ws_address: Self::parse_string("WS_ADDRESS", &env_map)?,
-            ws_apikey: Self::parse_string("WS_APIKEY", &env_map)?,
-            ws_password: Self::parse_string("WS_PASSWORD", &env_map)?,
-            ws_token_address: Self::parse_string("WS_TOKEN_ADDRESS", &env_map)?,
+            ws,
+            ws_token_ttl: Self::parse_millis("WS_TOKEN_TTL_MS", &env_map, 60_000),
         })
     }
 
-    pub fn get() -> Self {
-        let local_env = ".env";
-        let app_env = "/app_env/.env";
+    /// Load the first of the .env files that exists into the environment. A missing file isn't an error, as the envs may
+    /// already be set, such as by the systemd `EnvironmentFile=`, any required env that is absent fails when parsed
+    fn load_file(paths: &[&str]) {
+        if let Some(path) = paths
+            .iter()
+            .find(|i| std::fs::exists(i).unwrap_or_default())
+        {
+            dotenvy::from_path(path).ok();
+        }
+    }
 
-        let env_path = if std::fs::exists(app_env).unwrap_or_default() {
-            app_env
-        } else if std::fs::exists(local_env).unwrap_or_default() {
-            local_env
-        } else {
-            println!("\n\x1b[31munable to load env file\x1b[0m\n");
-            std::process::exit(1);
+    fn get_from(paths: &[&str]) -> Result<Self, AppError> {
+        Self::load_file(paths);
+        Self::generate()
+    }
+
+    /// Load the .env file, if there is one, and parse into `AppEnv`
+    pub fn get() -> Result<Self, AppError> {
+        Self::get_from(&ENV_FILES)
+    }
+
+    /// Read the envs from a .env file, without loading them into the environment
+    fn read_file(path: &Path) -> Result<EnvHashMap, AppError> {
+        let error = |e: dotenvy::Error| {
+            AppError::Io(std::io::Error::other(format!("{}: {e}", path.display())))
         };
+        dotenvy::from_path_iter(path)
+            .map_err(error)?
+            .map(|i| i.map_err(error))
+            .collect()
+    }
 
-        dotenvy::from_path(env_path).ok();
-        match Self::generate() {
-            Ok(s) => s,
-            Err(e) => {
-                println!("\n\x1b[31m{e}\x1b[0m\n");
-                std::process::exit(1);
-            }
+    /// Parse only the on and off times, used when installing timers, which don't need the WS envs. The envs are read as
+    /// the oneshot services will see them, from the .env file in the working directory, overridden by the unit's
+    /// `Environment=`, and then by its `EnvironmentFile=`, error if either time is missing, or invalid
+    pub fn get_schedule(
+        env_file: Option<&Path>,
+        environment: &[String],
+        working_dir: &Path,
+    ) -> Result<(Time, Time), AppError> {
+        let mut env_map = EnvHashMap::new();
+        if let Some(path) = ENV_FILES
+            .iter()
+            .map(|i| working_dir.join(i))
+            .find(|i| i.exists())
+        {
+            env_map.extend(Self::read_file(&path)?);
+        }
+        env_map.extend(
+            environment
+                .iter()
+                .filter_map(|i| i.split_once('='))
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        if let Some(path) = env_file {
+            env_map.extend(Self::read_file(path)?);
         }
+        Ok((
+            Self::try_parse_time("TIME_ON", &env_map)?,
+            Self::try_parse_time("TIME_OFF", &env_map)?,
+        ))
     }
 }
 
@@ -187,7 +526,76 @@ mod tests {
 
         let result = AppEnv::parse_time("TIME_OFF", &map);
         assert_eq!(result, Time::constant(12, 0, 0, 0));
+
+        for value in ["", "7", "713", "07:13", "+713", "07130", "0760"] {
+            let map = HashMap::from([(S!("TIME_ON"), S!(value))]);
+            assert_eq!(
+                AppEnv::parse_time("TIME_ON", &map),
+                Time::constant(12, 0, 0, 0)
+            );
+            assert!(matches!(
+                AppEnv::try_parse_time("TIME_ON", &map),
+                Err(AppError::InvalidTime(key, v)) if key == "TIME_ON" && v == value
+            ));
+        }
+        assert!(matches!(
+            AppEnv::try_parse_time("TIME_ON", &HashMap::new()),
+            Err(AppError::MissingEnv(key)) if key == "TIME_ON"
+        ));
+
+        let map = HashMap::from([(S!("TIME_ON"), S!("2359"))]);
+        assert_eq!(
+            AppEnv::try_parse_time("TIME_ON", &map).unwrap(),
+            Time::constant(23, 59, 0, 0)
+        );
+    }
+
+    #[test]
+    fn env_get_schedule() {
+        let dir = std::env::temp_dir().join(format!(
+            "screen_control_test_schedule_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let env_file = dir.join("service.env");
+
+        // Nothing in the working directory, and no env file
+        let result = AppEnv::get_schedule(None, &[], &dir);
+        assert!(matches!(result, Err(AppError::MissingEnv(key)) if key == "TIME_ON"));
+
+        // The .env in the working directory, as loaded by the service
+        std::fs::write(dir.join(".env"), "TIME_ON=0700\nTIME_OFF=2100\n").unwrap();
+        let result = AppEnv::get_schedule(None, &[], &dir).unwrap();
+        assert_eq!(
+            result,
+            (Time::constant(7, 0, 0, 0), Time::constant(21, 0, 0, 0))
+        );
+
+        // Environment= overrides the .env, and EnvironmentFile= overrides both
+        std::fs::write(&env_file, "TIME_OFF=2230\n").unwrap();
+        let result = AppEnv::get_schedule(
+            Some(&env_file),
+            &[S!("TIME_ON=0815"), S!("TIME_OFF=2300")],
+            &dir,
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            (Time::constant(8, 15, 0, 0), Time::constant(22, 30, 0, 0))
+        );
+
+        // An invalid time in the env file is an error, rather than 12:00
+        std::fs::write(&env_file, "TIME_OFF=9\n").unwrap();
+        let result = AppEnv::get_schedule(Some(&env_file), &[], &dir);
+        assert!(matches!(result, Err(AppError::InvalidTime(key, _)) if key == "TIME_OFF"));
+
+        // A missing env file is an error
+        let result = AppEnv::get_schedule(Some(&dir.join("missing.env")), &[], &dir);
+        assert!(matches!(result, Err(AppError::Io(_))));
+
+        std::fs::remove_dir_all(&dir).ok();
     }
+
     #[test]
     fn env_parse_log_valid() {
         let map = HashMap::from([(S!("RANDOM_STRING"), S!("123"))]);
@@ -242,17 +650,369 @@ mod tests {
         assert_eq!(result, tracing::Level::TRACE);
     }
 
-    // Why?
+    #[test]
+    fn env_parse_backoff() {
+        let result = AppEnv::parse_backoff(&HashMap::new());
+        assert_eq!(
+            result,
+            BackoffConfig {
+                initial: Duration::from_secs(1),
+                max: Duration::from_secs(60),
+                reset_after: Duration::from_secs(30),
+            }
+        );
+
+        let map = HashMap::from([
+            (S!("WS_BACKOFF_INITIAL_MS"), S!("500")),
+            (S!("WS_BACKOFF_MAX_MS"), S!("120000")),
+            (S!("WS_BACKOFF_RESET_MS"), S!("fish")),
+        ]);
+        let result = AppEnv::parse_backoff(&map);
+        assert_eq!(result.initial, Duration::from_millis(500));
+        assert_eq!(result.max, Duration::from_secs(120));
+        assert_eq!(result.reset_after, Duration::from_secs(30));
+
+        // max less than initial
+        let map = HashMap::from([
+            (S!("WS_BACKOFF_INITIAL_MS"), S!("0")),
+            (S!("WS_BACKOFF_MAX_MS"), S!("0")),
+        ]);
+        let result = AppEnv::parse_backoff(&map);
+        assert_eq!(result.initial, Duration::from_millis(1));
+        assert_eq!(result.max, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn env_parse_proxy() {
+        assert_eq!(
+            AppEnv::parse_proxy(&HashMap::new()).unwrap(),
+            ProxyConfig::default()
+        );
+
+        let map = HashMap::from([
+            (S!("ALL_PROXY"), S!("socks5://10.0.0.1:1080")),
+            (S!("https_proxy"), S!("http://10.0.0.2:3128")),
+            (S!("no_proxy"), S!("localhost,.lan")),
+        ]);
+        let result = AppEnv::parse_proxy(&map).unwrap();
+        assert_eq!(result.proxy.unwrap().host, "10.0.0.2");
+        assert_eq!(result.no_proxy, ["localhost", ".lan"]);
+
+        let mut map = HashMap::from([
+            (S!("WS_PROXY"), S!("socks5h://10.0.0.3")),
+            (S!("HTTPS_PROXY"), S!("")),
+            (S!("ALL_PROXY"), S!("socks5://10.0.0.1:1080")),
+        ]);
+        let result = AppEnv::parse_proxy(&map).unwrap();
+        assert_eq!(result.proxy.unwrap().host, "10.0.0.3");
+
+        map.insert(S!("WS_PROXY"), S!("ftp://10.0.0.3"));
+        assert!(matches!(
+            AppEnv::parse_proxy(&map),
+            Err(AppError::InvalidProxy(_))
+        ));
+    }
+
+    #[test]
+    fn env_parse_timeouts() {
+        let result = AppEnv::parse_timeouts(&HashMap::new());
+        assert_eq!(
+            result,
+            TimeoutConfig {
+                token: Duration::from_secs(10),
+                connect: Duration::from_secs(10),
+                upgrade: Duration::from_secs(10),
+            }
+        );
+
+        let map = HashMap::from([
+            (S!("WS_TOKEN_TIMEOUT_MS"), S!("2500")),
+            (S!("WS_CONNECT_TIMEOUT_MS"), S!("0")),
+            (S!("WS_UPGRADE_TIMEOUT_MS"), S!("-1")),
+        ]);
+        let result = AppEnv::parse_timeouts(&map);
+        assert_eq!(result.token, Duration::from_millis(2500));
+        assert_eq!(result.connect, Duration::from_millis(1));
+        assert_eq!(result.upgrade, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn env_parse_endpoints() {
+        let map = HashMap::from([
+            (S!("WS_ADDRESS"), S!("wss://a.example.com")),
+            (S!("WS_TOKEN_ADDRESS"), S!("https://a.example.com/token")),
+        ]);
+        assert_eq!(
+            AppEnv::parse_endpoints(&map).unwrap(),
+            [Endpoint {
+                ws_address: S!("wss://a.example.com"),
+                token_address: S!("https://a.example.com/token"),
+            }]
+        );
+
+        let map = HashMap::from([
+            (
+                S!("WS_ADDRESS"),
+                S!("wss://a.example.com, wss://b.example.com,"),
+            ),
+            (
+                S!("WS_TOKEN_ADDRESS"),
+                S!("https://a.example.com/token,https://b.example.com/token"),
+            ),
+        ]);
+        let result = AppEnv::parse_endpoints(&map).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[1].ws_address, "wss://b.example.com");
+        assert_eq!(result[1].token_address, "https://b.example.com/token");
+
+        let map = HashMap::from([
+            (
+                S!("WS_ADDRESS"),
+                S!("wss://a.example.com,wss://b.example.com"),
+            ),
+            (S!("WS_TOKEN_ADDRESS"), S!("https://a.example.com/token")),
+        ]);
+        let result = AppEnv::parse_endpoints(&map);
+        assert!(matches!(result, Err(AppError::InvalidEndpoints(_))));
+        assert_eq!(result.unwrap_err().exit_code(), 3);
+
+        let map = HashMap::from([
+            (S!("WS_ADDRESS"), S!(" , ")),
+            (S!("WS_TOKEN_ADDRESS"), S!("")),
+        ]);
+        assert!(matches!(
+            AppEnv::parse_endpoints(&map),
+            Err(AppError::MissingEnv(_))
+        ));
+        assert!(matches!(
+            AppEnv::parse_endpoints(&HashMap::new()),
+            Err(AppError::MissingEnv(_))
+        ));
+    }
+
+    #[test]
+    fn env_parse_ws() {
+        let tls = TlsConfig::default();
+        assert!(AppEnv::parse_ws(&HashMap::new(), &tls).unwrap().is_none());
+        let map = HashMap::from([(S!("WS_ADDRESS"), S!(" ")), (S!("TIME_ON"), S!("0800"))]);
+        assert!(AppEnv::parse_ws(&map, &tls).unwrap().is_none());
+
+        let mut map = HashMap::from([
+            (S!("WS_ADDRESS"), S!("wss://a.example.com")),
+            (S!("WS_APIKEY"), S!("apikey")),
+            (S!("WS_PASSWORD"), S!("password")),
+            (S!("WS_TOKEN_ADDRESS"), S!("https://a.example.com/token")),
+        ]);
+        assert_eq!(
+            AppEnv::parse_ws(&map, &tls).unwrap(),
+            Some(WsConfig {
+                apikey: S!("apikey"),
+                auth: WsAuth::Path,
+                endpoints: vec![Endpoint {
+                    ws_address: S!("wss://a.example.com"),
+                    token_address: S!("https://a.example.com/token"),
+                }],
+                password: Some(S!("password")),
+            })
+        );
+
+        map.insert(S!("WS_AUTH"), S!("Bearer"));
+        assert_eq!(
+            AppEnv::parse_ws(&map, &tls).unwrap().unwrap().auth,
+            WsAuth::Bearer
+        );
+        map.insert(S!("WS_AUTH"), S!("protocol"));
+        assert_eq!(
+            AppEnv::parse_ws(&map, &tls).unwrap().unwrap().auth,
+            WsAuth::Protocol
+        );
+        map.insert(S!("WS_AUTH"), S!("query"));
+        assert!(matches!(
+            AppEnv::parse_ws(&map, &tls),
+            Err(AppError::InvalidAuth(auth)) if auth == "query"
+        ));
+        map.remove("WS_AUTH");
+
+        // Partially configured is an error, rather than offline
+        map.remove("WS_APIKEY");
+        assert!(matches!(
+            AppEnv::parse_ws(&map, &tls),
+            Err(AppError::MissingEnv(key)) if key == "WS_APIKEY"
+        ));
+    }
+
+    #[test]
+    fn env_parse_failover() {
+        assert_eq!(
+            AppEnv::parse_failover(&HashMap::new()),
+            FailoverConfig {
+                probe_interval: Duration::from_secs(30),
+                failback_after: Duration::from_secs(300),
+            }
+        );
+        let map = HashMap::from([
+            (S!("WS_PROBE_INTERVAL_MS"), S!("0")),
+            (S!("WS_FAILBACK_MS"), S!("60000")),
+        ]);
+        assert_eq!(
+            AppEnv::parse_failover(&map),
+            FailoverConfig {
+                probe_interval: Duration::from_millis(1),
+                failback_after: Duration::from_secs(60),
+            }
+        );
+    }
+
+    #[test]
+    fn env_parse_heartbeat() {
+        let result = AppEnv::parse_heartbeat(&HashMap::new()).unwrap();
+        assert_eq!(
+            result,
+            HeartbeatConfig {
+                interval: Some(Duration::from_secs(15)),
+                missed: 3,
+                auto_close: Some(Duration::from_secs(40)),
+            }
+        );
+
+        let map = HashMap::from([
+            (S!("WS_PING_INTERVAL_MS"), S!("5000")),
+            (S!("WS_PING_MISSED"), S!("0")),
+            (S!("WS_AUTOCLOSE_MS"), S!("0")),
+        ]);
+        let result = AppEnv::parse_heartbeat(&map).unwrap();
+        assert_eq!(result.interval, Some(Duration::from_secs(5)));
+        assert_eq!(result.missed, 1);
+        assert!(result.auto_close.is_none());
+
+        let map = HashMap::from([
+            (S!("WS_PING_INTERVAL_MS"), S!("0")),
+            (S!("WS_PING_MISSED"), S!("five")),
+        ]);
+        let result = AppEnv::parse_heartbeat(&map).unwrap();
+        assert!(result.interval.is_none());
+        assert_eq!(result.missed, 3);
+
+        // Both disabled would never detect a dead connection
+        let map = HashMap::from([
+            (S!("WS_PING_INTERVAL_MS"), S!("0")),
+            (S!("WS_AUTOCLOSE_MS"), S!("0")),
+        ]);
+        assert!(matches!(
+            AppEnv::parse_heartbeat(&map),
+            Err(AppError::InvalidHeartbeat)
+        ));
+    }
+
+    #[test]
+    fn env_parse_outbox() {
+        let result = AppEnv::parse_outbox(&HashMap::new());
+        assert_eq!(
+            result,
+            OutboxConfig {
+                size: 100,
+                path: None,
+            }
+        );
+
+        let map = HashMap::from([
+            (S!("WS_OUTBOX_SIZE"), S!("0")),
+            (
+                S!("WS_OUTBOX_FILE"),
+                S!("/var/lib/screen_control/outbox.json"),
+            ),
+        ]);
+        let result = AppEnv::parse_outbox(&map);
+        assert_eq!(result.size, 0);
+        assert_eq!(
+            result.path,
+            Some(PathBuf::from("/var/lib/screen_control/outbox.json"))
+        );
+
+        let map = HashMap::from([
+            (S!("WS_OUTBOX_SIZE"), S!("-1")),
+            (S!("WS_OUTBOX_FILE"), S!(" ")),
+        ]);
+        let result = AppEnv::parse_outbox(&map);
+        assert_eq!(result.size, 100);
+        assert!(result.path.is_none());
+    }
+
+    #[test]
+    fn env_parse_tls() {
+        let result = AppEnv::parse_tls(&HashMap::new()).unwrap();
+        assert_eq!(result, TlsConfig::default());
+        assert!(matches!(
+            AppEnv::parse_password(&HashMap::new(), &result),
+            Err(AppError::MissingEnv(_))
+        ));
+
+        let map = HashMap::from([
+            (S!("WS_CA_BUNDLE"), S!("/etc/screen_control/ca.pem")),
+            (S!("WS_CLIENT_CERT"), S!("/etc/screen_control/device.pem")),
+            (S!("WS_CLIENT_KEY"), S!("/etc/screen_control/device.key")),
+            (
+                S!("WS_SPKI_PINS"),
+                S!("sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="),
+            ),
+        ]);
+        let result = AppEnv::parse_tls(&map).unwrap();
+        assert_eq!(
+            result,
+            TlsConfig {
+                ca_bundle: Some(PathBuf::from("/etc/screen_control/ca.pem")),
+                client_cert: Some((
+                    PathBuf::from("/etc/screen_control/device.pem"),
+                    PathBuf::from("/etc/screen_control/device.key")
+                )),
+                spki_pins: vec![[0; 32]],
+            }
+        );
+        // A client certificate replaces the password
+        assert!(AppEnv::parse_password(&map, &result).unwrap().is_none());
+
+        let map = HashMap::from([(S!("WS_CLIENT_CERT"), S!("/etc/screen_control/device.pem"))]);
+        assert!(matches!(AppEnv::parse_tls(&map), Err(AppError::Tls(_))));
+
+        let map = HashMap::from([(S!("WS_SPKI_PINS"), S!("invalid"))]);
+        assert!(matches!(AppEnv::parse_tls(&map), Err(AppError::Tls(_))));
+    }
+
+    #[test]
+    fn env_parse_journald() {
+        let map = HashMap::from([(S!("LOG_JOURNALD"), S!("false"))]);
+        assert!(AppEnv::parse_journald(&map).is_none());
+
+        let map = HashMap::from([(S!("LOG_JOURNALD"), S!("true"))]);
+        assert_eq!(
+            AppEnv::parse_journald(&map),
+            Some(PathBuf::from("/run/systemd/journal/socket"))
+        );
+
+        let map = HashMap::from([(S!("LOG_JOURNALD_SOCKET"), S!("/tmp/journal.sock"))]);
+        assert_eq!(
+            AppEnv::parse_journald(&map),
+            Some(PathBuf::from("/tmp/journal.sock"))
+        );
+    }
+
+    /// Both steps change the process env, so must be run in order, in a single test
     #[tokio::test]
-    async fn env_panic_appenv() {
-        let result = AppEnv::generate();
+    async fn env_get_from_process_env() {
+        let missing = ["/nonexistent/screen_control/.env"];
 
-        assert!(result.is_err());
+        // No .env file, and without any WS env the daemon runs offline
+        let result = AppEnv::get_from(&missing);
+
+        assert!(result.unwrap().ws.is_none());
 
+        // As systemd's EnvironmentFile=, the envs are set in the process env, but there's still no .env file
         dotenvy::dotenv().ok();
 
-        let result = AppEnv::generate();
+        let result = AppEnv::get_from(&missing);
 
-        assert!(result.is_ok());
+        let ws = result.unwrap().ws.unwrap();
+        assert_eq!(ws.apikey, "apikey");
+        assert_eq!(ws.endpoints[0].ws_address, "ws://127.0.0.1:9999");
     }
 }
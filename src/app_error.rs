@@ -2,16 +2,127 @@ use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum AppError {
+    #[error("Control socket: {0}")]
+    ControlSocket(String),
+    #[error("TCP/TLS connect timed out after {0}ms")]
+    ConnectTimeout(u64),
+    #[error("daemon not running, required for timed screen changes")]
+    DaemonRequired,
+    #[error("{0} doctor check(s) failed")]
+    DoctorFailed(usize),
+    #[error("invalid WS_AUTH: '{0}', expected path, bearer, or protocol")]
+    InvalidAuth(String),
+    #[error("WS_PING_INTERVAL_MS and WS_AUTOCLOSE_MS can't both be 0")]
+    InvalidHeartbeat,
+    #[error("invalid endpoints: {0}")]
+    InvalidEndpoints(String),
+    #[error("invalid proxy: '{0}'")]
+    InvalidProxy(String),
+    #[error("invalid {0}: '{1}', expected HHMM")]
+    InvalidTime(String, String),
     #[error("IO Error: '{0}'")]
     Io(#[from] std::io::Error),
-    #[error("Internal error: '{0}'")]
-    Internal(String),
+    #[error("invalid user, unable to get SUDO_USER")]
+    InvalidUser,
     #[error("missing env: '{0}'")]
     MissingEnv(String),
-    #[error("Reqwest Error")]
+    #[error("not running as sudo")]
+    NotRoot,
+    #[error("Proxy connect: '{0}'")]
+    ProxyConnect(String),
+    #[error("Reqwest Error: '{0}'")]
     Reqwest(#[from] reqwest::Error),
+    #[error("Screen command failed: '{0}'")]
+    ScreenCommand(String),
+    #[error("unable to determine screen status")]
+    ScreenStatusUnknown,
+    #[error("'{0}' failed: '{1}'")]
+    Systemd(String, String),
+    #[error("TLS config: '{0}'")]
+    Tls(String),
+    #[error("invalid token response: '{0}'")]
+    TokenResponse(String),
+    #[error("token server error, status {0}: '{1}'")]
+    TokenServer(u16, String),
+    #[error("token request failed, status {0}: '{1}'")]
+    TokenStatus(u16, String),
+    #[error("token request timed out after {0}ms")]
+    TokenTimeout(u64),
+    #[error("token request unauthorized, status {0}: '{1}'")]
+    TokenUnauthorized(u16, String),
+    #[error("user services must be installed without sudo")]
+    UserScopeAsRoot,
     #[error("WS Connect: {0}")]
     TungsteniteConnect(String),
+    #[error("WS upgrade timed out after {0}ms")]
+    UpgradeTimeout(u64),
     #[error("Invalid WS Status Code")]
     WsStatus,
 }
+
+impl AppError {
+    /// The process exit code for each error, documented in the cli help text
+    pub const fn exit_code(&self) -> u8 {
+        match self {
+            Self::InvalidAuth(_)
+            | Self::InvalidEndpoints(_)
+            | Self::InvalidHeartbeat
+            | Self::InvalidProxy(_)
+            | Self::InvalidTime(..)
+            | Self::MissingEnv(_)
+            | Self::Tls(_) => 3,
+            Self::InvalidUser | Self::NotRoot | Self::UserScopeAsRoot => 4,
+            Self::ScreenCommand(_) | Self::ScreenStatusUnknown => 5,
+            Self::DaemonRequired => 6,
+            Self::DoctorFailed(_) => 7,
+            Self::ConnectTimeout(_)
+            | Self::ControlSocket(_)
+            | Self::Io(_)
+            | Self::ProxyConnect(_)
+            | Self::Reqwest(_)
+            | Self::Systemd(..)
+            | Self::TokenResponse(_)
+            | Self::TokenServer(..)
+            | Self::TokenStatus(..)
+            | Self::TokenTimeout(_)
+            | Self::TokenUnauthorized(..)
+            | Self::TungsteniteConnect(_)
+            | Self::UpgradeTimeout(_)
+            | Self::WsStatus => 1,
+        }
+    }
+
+    /// A stable, machine readable, name for the error, as included in the status
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::ConnectTimeout(_) => "connect_timeout",
+            Self::ControlSocket(_) => "control_socket",
+            Self::DaemonRequired => "daemon_required",
+            Self::DoctorFailed(_) => "doctor_failed",
+            Self::InvalidAuth(_) => "invalid_auth",
+            Self::InvalidEndpoints(_) => "invalid_endpoints",
+            Self::InvalidHeartbeat => "invalid_heartbeat",
+            Self::InvalidProxy(_) => "invalid_proxy",
+            Self::InvalidTime(..) => "invalid_time",
+            Self::InvalidUser => "invalid_user",
+            Self::Io(_) => "io",
+            Self::MissingEnv(_) => "missing_env",
+            Self::NotRoot => "not_root",
+            Self::ProxyConnect(_) => "proxy_connect",
+            Self::Reqwest(_) => "token_request",
+            Self::ScreenCommand(_) => "screen_command",
+            Self::ScreenStatusUnknown => "screen_status_unknown",
+            Self::Systemd(..) => "systemd",
+            Self::Tls(_) => "tls",
+            Self::TokenResponse(_) => "token_response",
+            Self::TokenServer(..) => "token_server",
+            Self::TokenStatus(..) => "token_status",
+            Self::TokenTimeout(_) => "token_timeout",
+            Self::TokenUnauthorized(..) => "token_unauthorized",
+            Self::TungsteniteConnect(_) => "ws_connect",
+            Self::UpgradeTimeout(_) => "upgrade_timeout",
+            Self::UserScopeAsRoot => "user_scope_as_root",
+            Self::WsStatus => "ws_status",
+        }
+    }
+}
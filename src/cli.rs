@@ -0,0 +1,433 @@
+use std::{fmt, path::PathBuf, process::ExitCode, time::Duration};
+
+use clap::{Args, Parser, Subcommand};
+use jiff::SignedDuration;
+use serde::Serialize;
+
+use crate::{
+    S,
+    app_error::AppError,
+    systemd::{Harden, Restart},
+    ws_messages::{PendingRevert, ScreenStatus},
+};
+
+/// Documented exit codes, the mapping from errors is in `AppError::exit_code()`
+const EXIT_CODES: &str = "Exit codes:
+  0  Success
+  1  General error
+  2  Invalid command line usage
+  3  Env missing, or invalid
+  4  Insufficient, or excess, permissions, or invalid user
+  5  Screen command failed, or screen status unknown
+  6  Daemon not running, required for timed screen changes
+  7  One or more doctor checks failed";
+
+#[derive(Debug, Parser)]
+#[command(version, about, after_help = EXIT_CODES)]
+pub struct Cli {
+    /// Output results as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum CliCommand {
+    /// Connect to the WS server as a long running process, the default if no command given
+    Run,
+    /// Turn screen on
+    On {
+        /// Revert to the previous screen status after the given duration, e.g. `20m` or `1h 30m`, requires the daemon to be running
+        #[arg(long = "for", value_parser = parse_duration)]
+        duration: Option<Duration>,
+    },
+    /// Turn screen off
+    Off {
+        /// Revert to the previous screen status after the given duration, e.g. `20m` or `1h 30m`, requires the daemon to be running
+        #[arg(long = "for", value_parser = parse_duration)]
+        duration: Option<Duration>,
+    },
+    /// Turn screen on if currently off, or off if currently on
+    Toggle {
+        /// Revert to the previous screen status after the given duration, e.g. `20m` or `1h 30m`, requires the daemon to be running
+        #[arg(long = "for", value_parser = parse_duration)]
+        duration: Option<Duration>,
+    },
+    /// Display the screen status, schedule, IP address, uptimes, and version
+    Status,
+    /// Install systemd service, requires running as SUDO, unless --user
+    Install(InstallArgs),
+    /// Uninstall systemd service, requires running as SUDO, unless --user
+    Uninstall {
+        /// Uninstall the systemd user service. Run without SUDO
+        #[arg(long)]
+        user: bool,
+    },
+    /// Display the configured on/off schedule
+    Schedule,
+    /// Check each dependency of the daemon, and display a pass/fail report
+    Doctor,
+}
+
+/// Options used to generate the systemd unit file
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct InstallArgs {
+    /// Install as a systemd user service, started with the graphical session, and enable lingering. Run without SUDO
+    #[arg(long)]
+    pub user: bool,
+    /// Path of the executable, defaults to the current executable
+    #[arg(long)]
+    pub bin_path: Option<PathBuf>,
+    /// Working directory of the service, defaults to the directory of the executable
+    #[arg(long)]
+    pub working_dir: Option<PathBuf>,
+    /// Env file to load, via EnvironmentFile=
+    #[arg(long)]
+    pub env_file: Option<PathBuf>,
+    /// Extra environment variable for the service, can be repeated
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env)]
+    pub env: Vec<String>,
+    /// User to run the service as, defaults to the SUDO user
+    #[arg(long, conflicts_with = "user")]
+    pub service_user: Option<String>,
+    /// Group to run the service as, defaults to the service user
+    #[arg(long, conflicts_with = "user")]
+    pub service_group: Option<String>,
+    /// Restart policy of the service
+    #[arg(long, value_enum, default_value_t = Restart::Always)]
+    pub restart: Restart,
+    /// Seconds to wait before restarting the service
+    #[arg(long, default_value_t = 5)]
+    pub restart_sec: u32,
+    /// Seconds without a watchdog ping from the daemon before systemd restarts it, 0 to disable.
+    /// Reconnecting happens in its own task, so the message loop keeps pinging throughout a WS outage, this only needs to
+    /// exceed the longest the message loop can be busy, such as running a screen command, the default leaves ample margin
+    #[arg(long, default_value_t = 120)]
+    pub watchdog_sec: u32,
+    /// Remove a hardening option from the unit, can be repeated, `all` removes every option
+    #[arg(long, value_enum, value_name = "OPTION")]
+    pub no_harden: Vec<Harden>,
+    /// Install systemd timers, which run the on and off commands at the TIME_ON and TIME_OFF of the env file, instead of the daemon
+    #[arg(long)]
+    pub timers: bool,
+    /// Print the unit files, and their locations, without installing. Doesn't require SUDO
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Validate an environment variable, in the form `KEY=VALUE`, with a non-empty key
+fn parse_env(input: &str) -> Result<String, String> {
+    match input.split_once('=') {
+        Some((key, _)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+            Ok(input.to_owned())
+        }
+        _ => Err(S!("expected KEY=VALUE")),
+    }
+}
+
+/// Parse a human duration, e.g. `90s`, `20m`, or `1h 30m`, into a positive whole number of seconds
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    let duration = input.parse::<SignedDuration>().map_err(|e| e.to_string())?;
+    Duration::try_from(duration)
+        .ok()
+        .filter(|i| i.as_secs() > 0)
+        .map(|i| Duration::from_secs(i.as_secs()))
+        .ok_or_else(|| S!("duration must be at least one second"))
+}
+
+impl Cli {
+    /// Convert the pre-subcommand flags, e.g `-i` or `--on`, into their subcommand equivalent
+    fn legacy_arg(arg: String) -> String {
+        match arg.as_str() {
+            "-i" => "install".to_owned(),
+            "-u" => "uninstall".to_owned(),
+            "--on" => "on".to_owned(),
+            "--off" => "off".to_owned(),
+            _ => arg,
+        }
+    }
+
+    /// Only the first argument, after the binary name, can be a legacy flag, so option values are never rewritten
+    fn legacy_args(args: impl IntoIterator<Item = String>) -> Vec<String> {
+        args.into_iter()
+            .enumerate()
+            .map(|(index, arg)| {
+                if index == 1 {
+                    Self::legacy_arg(arg)
+                } else {
+                    arg
+                }
+            })
+            .collect()
+    }
+
+    /// Parse the command line arguments, will exit with code 2 on invalid input
+    pub fn get() -> Self {
+        Self::parse_from(Self::legacy_args(std::env::args()))
+    }
+}
+
+/// The result of an on/off/toggle command, `daemon` is true if handled by the running daemon
+#[derive(Debug, Serialize)]
+pub struct ScreenChange {
+    pub screen_status: ScreenStatus,
+    pub daemon: bool,
+    pub revert: Option<PendingRevert>,
+}
+
+impl fmt::Display for ScreenChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let via = if self.daemon { "daemon" } else { "direct" };
+        write!(f, "screen: {} (via {via})", self.screen_status)?;
+        if let Some(revert) = &self.revert {
+            write!(f, ", reverting to {revert}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of a command which has no output of its own, e.g. install
+#[derive(Debug, Serialize)]
+pub struct Done {
+    pub command: &'static str,
+}
+
+impl fmt::Display for Done {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: done", self.command)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorOutput {
+    error: String,
+    exit_code: u8,
+}
+
+/// Print the result of a command, either as JSON or in its human readable form
+pub fn print_output<T: Serialize + fmt::Display>(json: bool, output: &T) {
+    if json {
+        println!("{}", serde_json::to_string(output).unwrap_or_default());
+    } else {
+        println!("{output}");
+    }
+}
+
+/// Print the error, and convert into the documented exit code
+pub fn print_error(json: bool, error: &AppError) -> ExitCode {
+    let exit_code = error.exit_code();
+    if json {
+        let output = ErrorOutput {
+            error: error.to_string(),
+            exit_code,
+        };
+        println!("{}", serde_json::to_string(&output).unwrap_or_default());
+    } else {
+        eprintln!("\n\x1b[31m{error}\x1b[0m\n");
+    }
+    ExitCode::from(exit_code)
+}
+
+/// cargo watch -q -c -w src/ -x 'test cli_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn default_install(user: bool) -> CliCommand {
+        CliCommand::Install(InstallArgs {
+            user,
+            bin_path: None,
+            working_dir: None,
+            env_file: None,
+            env: vec![],
+            service_user: None,
+            service_group: None,
+            restart: Restart::Always,
+            restart_sec: 5,
+            watchdog_sec: 120,
+            no_harden: vec![],
+            timers: false,
+            dry_run: false,
+        })
+    }
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(Cli::legacy_args(
+            std::iter::once("screen_control")
+                .chain(args.iter().copied())
+                .map(ToOwned::to_owned),
+        ))
+    }
+
+    #[test]
+    fn cli_parse_subcommands() {
+        for (arg, command) in [
+            ("run", CliCommand::Run),
+            ("on", CliCommand::On { duration: None }),
+            ("off", CliCommand::Off { duration: None }),
+            ("toggle", CliCommand::Toggle { duration: None }),
+            ("status", CliCommand::Status),
+            ("install", default_install(false)),
+            ("uninstall", CliCommand::Uninstall { user: false }),
+            ("schedule", CliCommand::Schedule),
+            ("doctor", CliCommand::Doctor),
+        ] {
+            let result = parse(&[arg]).unwrap();
+            assert_eq!(result.command, Some(command));
+            assert!(!result.json);
+        }
+
+        let result = parse(&[]).unwrap();
+        assert!(result.command.is_none());
+    }
+
+    #[test]
+    fn cli_parse_json() {
+        let result = parse(&["status", "--json"]).unwrap();
+        assert_eq!(result.command, Some(CliCommand::Status));
+        assert!(result.json);
+
+        let result = parse(&["--json", "on"]).unwrap();
+        assert_eq!(result.command, Some(CliCommand::On { duration: None }));
+        assert!(result.json);
+    }
+
+    #[test]
+    fn cli_parse_duration() {
+        let result = parse(&["on", "--for", "20m"]).unwrap();
+        assert_eq!(
+            result.command,
+            Some(CliCommand::On {
+                duration: Some(Duration::from_secs(1200))
+            })
+        );
+
+        let result = parse(&["off", "--for", "1h 30m"]).unwrap();
+        assert_eq!(
+            result.command,
+            Some(CliCommand::Off {
+                duration: Some(Duration::from_secs(5400))
+            })
+        );
+
+        let result = parse(&["toggle", "--for", "90s", "--json"]).unwrap();
+        assert_eq!(
+            result.command,
+            Some(CliCommand::Toggle {
+                duration: Some(Duration::from_secs(90))
+            })
+        );
+
+        for invalid in ["20", "0s", "-5m", "fish", "500ms"] {
+            assert!(parse(&["on", "--for", invalid]).is_err());
+        }
+    }
+
+    #[test]
+    fn cli_parse_legacy() {
+        for (arg, command) in [
+            ("-i", default_install(false)),
+            ("-u", CliCommand::Uninstall { user: false }),
+            ("--on", CliCommand::On { duration: None }),
+            ("--off", CliCommand::Off { duration: None }),
+        ] {
+            assert_eq!(parse(&[arg]).unwrap().command, Some(command));
+        }
+
+        // Only the first argument is rewritten, a value which looks like a legacy flag is left as is
+        assert_eq!(
+            Cli::legacy_args(["screen_control", "install", "--bin-path", "-i"].map(String::from)),
+            ["screen_control", "install", "--bin-path", "-i"]
+        );
+        assert!(parse(&["install", "--bin-path", "-i"]).is_err());
+        assert!(parse(&["install", "--service-user", "--on"]).is_err());
+        assert!(parse(&["--json", "--on"]).is_err());
+    }
+
+    #[test]
+    fn cli_parse_user_scope() {
+        let result = parse(&["install", "--user"]).unwrap();
+        assert_eq!(result.command, Some(default_install(true)));
+
+        let result = parse(&["uninstall", "--user"]).unwrap();
+        assert_eq!(result.command, Some(CliCommand::Uninstall { user: true }));
+
+        assert!(parse(&["on", "--user"]).is_err());
+    }
+
+    #[test]
+    fn cli_parse_install_options() {
+        let result = parse(&[
+            "install",
+            "--bin-path",
+            "/opt/screen_control",
+            "--working-dir",
+            "/var/lib/screen_control",
+            "--env-file",
+            "/etc/screen_control.env",
+            "--env",
+            "LOG_DEBUG=true",
+            "--env",
+            "EMPTY=",
+            "--service-user",
+            "pi",
+            "--service-group",
+            "video",
+            "--restart",
+            "on-failure",
+            "--restart-sec",
+            "30",
+            "--watchdog-sec",
+            "0",
+            "--no-harden",
+            "protect-home",
+            "--no-harden",
+            "system-call-filter",
+            "--timers",
+            "--dry-run",
+        ])
+        .unwrap();
+        assert_eq!(
+            result.command,
+            Some(CliCommand::Install(InstallArgs {
+                user: false,
+                bin_path: Some(PathBuf::from("/opt/screen_control")),
+                working_dir: Some(PathBuf::from("/var/lib/screen_control")),
+                env_file: Some(PathBuf::from("/etc/screen_control.env")),
+                env: vec![S!("LOG_DEBUG=true"), S!("EMPTY=")],
+                service_user: Some(S!("pi")),
+                service_group: Some(S!("video")),
+                restart: Restart::OnFailure,
+                restart_sec: 30,
+                watchdog_sec: 0,
+                no_harden: vec![Harden::ProtectHome, Harden::SystemCallFilter],
+                timers: true,
+                dry_run: true,
+            }))
+        );
+
+        for invalid in [
+            &["install", "--env", "LOG_DEBUG"][..],
+            &["install", "--env", "=true"],
+            &["install", "--restart", "sometimes"],
+            &["install", "--no-harden", "everything"],
+            &["install", "--restart-sec", "-1"],
+            &["install", "--user", "--service-user", "pi"],
+        ] {
+            assert!(parse(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn cli_parse_invalid() {
+        let result = parse(&["fish"]);
+        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().exit_code(), 2);
+
+        let result = parse(&["-x"]);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use async_channel::Sender;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{
+    C, S,
+    app_error::AppError,
+    message_handler::Msg,
+    ws_messages::{
+        MessageValues, ParsedMessage, Response, StructuredMessage, StructuredResponse, to_struct,
+    },
+};
+
+const SOCKET_NAME: &str = "screen_control.sock";
+
+/// Created by systemd, via RuntimeDirectory, for a system service whose user's runtime directory couldn't be resolved
+pub const SYSTEM_RUNTIME_DIR: &str = "/run/screen_control";
+
+/// How long the cli will wait for the daemon to respond
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Location of the control socket, in XDG_RUNTIME_DIR if set, else the system runtime directory if it exists, else the
+/// temp directory
+pub fn socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .or_else(|| {
+            let dir = PathBuf::from(SYSTEM_RUNTIME_DIR);
+            dir.is_dir().then_some(dir)
+        })
+        .unwrap_or_else(std::env::temp_dir)
+        .join(SOCKET_NAME)
+}
+
+/// Location of the socket of the running daemon, as `socket_path`, unless only the socket in the system runtime directory
+/// exists, i.e. the daemon is a system service without XDG_RUNTIME_DIR, but the cli is run in a login session
+pub fn daemon_socket_path() -> PathBuf {
+    let path = socket_path();
+    let system = Path::new(SYSTEM_RUNTIME_DIR).join(SOCKET_NAME);
+    if !path.exists() && system.exists() {
+        system
+    } else {
+        path
+    }
+}
+
+/// Unix socket listener, so that cli commands can be handled by the running daemon
+pub struct ControlServer;
+
+impl ControlServer {
+    /// Bind to the control socket, removing any stale socket file, and spawn a task to accept connections
+    pub async fn start(path: &Path, tx: &Sender<Msg>) -> Result<(), AppError> {
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(AppError::ControlSocket(S!("daemon already running")));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+        let tx = C!(tx);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let tx = C!(tx);
+                tokio::spawn(async move {
+                    Self::on_connection(stream, tx).await;
+                });
+            }
+        });
+        Ok(())
+    }
+
+    /// Remove the socket file, called on exit
+    pub fn remove(path: &Path) {
+        std::fs::remove_file(path).ok();
+    }
+
+    /// Each line sent by the client is a StructuredMessage, reply to each with a single line StructuredResponse
+    async fn on_connection(stream: UnixStream, tx: Sender<Msg>) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let response = match to_struct(&line) {
+                Some(MessageValues::Valid(message, unique)) => {
+                    tracing::info!(event = message.name(), unique, "control message received");
+                    Self::forward(message, &tx).await
+                }
+                _ => StructuredResponse::error_json(Response::Error(S!("invalid message"))),
+            };
+            if writer
+                .write_all(format!("{response}\n").as_bytes())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Send the message to the message handler, and await its reply
+    async fn forward(message: ParsedMessage, tx: &Sender<Msg>) -> String {
+        let (reply_tx, reply_rx) = async_channel::bounded(1);
+        if tx.send(Msg::Control(message, reply_tx)).await.is_err() {
+            return StructuredResponse::error_json(Response::Error(S!("daemon closing")));
+        }
+        match reply_rx.recv().await {
+            Ok(Response::Error(e)) => StructuredResponse::error_json(Response::Error(e)),
+            Ok(response) => StructuredResponse::data_json(response),
+            Err(_) => StructuredResponse::error_json(Response::Error(S!("no response"))),
+        }
+    }
+}
+
+/// Used by the cli to send commands to the running daemon
+pub struct ControlClient;
+
+impl ControlClient {
+    /// Send a message to the daemon, and await its response, `None` if the daemon isn't running
+    pub async fn send(path: &Path, message: ParsedMessage) -> Result<Option<Response>, AppError> {
+        let Ok(stream) = UnixStream::connect(path).await else {
+            return Ok(None);
+        };
+        tokio::time::timeout(RESPONSE_TIMEOUT, Self::request(stream, message))
+            .await
+            .map_err(|_| AppError::ControlSocket(S!("timeout awaiting daemon response")))?
+            .map(Some)
+    }
+
+    async fn request(stream: UnixStream, message: ParsedMessage) -> Result<Response, AppError> {
+        let (reader, mut writer) = stream.into_split();
+        let unique = format!("cli-{}", std::process::id());
+        writer
+            .write_all(format!("{}\n", StructuredMessage::to_json(message, &unique)).as_bytes())
+            .await?;
+        let line = BufReader::new(reader)
+            .lines()
+            .next_line()
+            .await?
+            .ok_or_else(|| AppError::ControlSocket(S!("connection closed by daemon")))?;
+        serde_json::from_str::<StructuredResponse>(&line)
+            .ok()
+            .and_then(StructuredResponse::into_response)
+            .ok_or_else(|| AppError::ControlSocket(S!("invalid response from daemon")))
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test control_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+mod tests {
+    use crate::ws_messages::ScreenBody;
+
+    use super::*;
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "screen_control_test_{name}_{}.sock",
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    /// Stand-in for the message handler, reply to screen commands with an error, and to everything else with the input name
+    fn test_handler(rx: async_channel::Receiver<Msg>) {
+        tokio::spawn(async move {
+            while let Ok(msg) = rx.recv().await {
+                if let Msg::Control(message, reply) = msg {
+                    let response = match message {
+                        ParsedMessage::ScreenOn(_) => Response::Error(S!("busctl missing")),
+                        _ => Response::Error(format!("{message:?}")),
+                    };
+                    reply.send(response).await.ok();
+                }
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn control_no_daemon() {
+        let path = test_path("no_daemon");
+        let result = ControlClient::send(&path, ParsedMessage::Status).await;
+        assert!(result.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn control_round_trip() {
+        let path = test_path("round_trip");
+        let (tx, rx) = async_channel::bounded(16);
+        ControlServer::start(&path, &tx).await.unwrap();
+        test_handler(rx);
+
+        let result = ControlClient::send(&path, ParsedMessage::ScreenOn(None)).await;
+        match result.unwrap() {
+            Some(Response::Error(e)) => assert_eq!(e, "busctl missing"),
+            _ => unreachable!("this indicates the test has failed"),
+        }
+
+        let body = ScreenBody::from(Duration::from_secs(60));
+        let result = ControlClient::send(&path, ParsedMessage::ScreenOff(Some(body))).await;
+        match result.unwrap() {
+            Some(Response::Error(e)) => {
+                assert_eq!(e, "ScreenOff(Some(ScreenBody { duration: 60 }))");
+            }
+            _ => unreachable!("this indicates the test has failed"),
+        }
+
+        // A second daemon is unable to start
+        let result = ControlServer::start(&path, &tx).await;
+        assert!(result.is_err());
+
+        ControlServer::remove(&path);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn control_stale_socket() {
+        let path = test_path("stale");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let (tx, rx) = async_channel::bounded(16);
+        ControlServer::start(&path, &tx).await.unwrap();
+        test_handler(rx);
+
+        let result = ControlClient::send(&path, ParsedMessage::Status).await;
+        assert!(result.unwrap().is_some());
+        ControlServer::remove(&path);
+    }
+
+    #[tokio::test]
+    async fn control_invalid_message() {
+        let path = test_path("invalid");
+        let (tx, _rx) = async_channel::bounded(16);
+        ControlServer::start(&path, &tx).await.unwrap();
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(b"not json\n").await.unwrap();
+        let line = BufReader::new(reader).lines().next_line().await.unwrap();
+        assert_eq!(
+            line.unwrap(),
+            r#"{"data":null,"error":{"name":"error","data":"invalid message"}}"#
+        );
+        ControlServer::remove(&path);
+    }
+}
@@ -1,7 +1,15 @@
+use std::fmt;
+
 use async_channel::Sender;
-use jiff::{Zoned, civil::Time};
+use jiff::{ToSpan, Zoned, civil::Time};
+use serde::Serialize;
 
-use crate::{C, app_env::AppEnv, message_handler::Msg, sleep};
+use crate::{
+    C,
+    app_env::AppEnv,
+    message_handler::{Msg, Source},
+    sleep,
+};
 pub struct Croner;
 
 impl Croner {
@@ -19,15 +27,106 @@ impl Croner {
                 && current_time.minute() == on.minute()
                 && current_time.second() == 0
             {
-                tx.send(Msg::ScreenOn).await.ok();
+                tx.send(Msg::ScreenOn(None, Source::Schedule)).await.ok();
             }
             if current_time.hour() == off.hour()
                 && current_time.minute() == off.minute()
                 && current_time.second() == 0
             {
-                tx.send(Msg::ScreenOff).await.ok();
+                tx.send(Msg::ScreenOff(None, Source::Schedule)).await.ok();
             }
             sleep!(250);
         }
     }
 }
+
+/// The configured on/off times, and when they will next occur
+#[derive(Debug, Serialize)]
+pub struct Schedule {
+    pub time_on: String,
+    pub time_off: String,
+    pub next_on: Option<String>,
+    pub next_off: Option<String>,
+}
+
+impl Schedule {
+    /// Get the next occurrence of a given time, either later today or tomorrow
+    fn next(now: &Zoned, time: Time) -> Option<Zoned> {
+        let today = now.with().time(time).build().ok()?;
+        if &today > now {
+            Some(today)
+        } else {
+            today.checked_add(1.day()).ok()
+        }
+    }
+
+    fn format_next(now: &Zoned, time: Time) -> Option<String> {
+        Self::next(now, time).map(|i| i.strftime("%Y-%m-%d %H:%M:%S %Z").to_string())
+    }
+
+    pub fn new(app_env: &AppEnv) -> Self {
+        let now = Zoned::now();
+        Self {
+            time_on: app_env.time_on.strftime("%H:%M").to_string(),
+            time_off: app_env.time_off.strftime("%H:%M").to_string(),
+            next_on: Self::format_next(&now, app_env.time_on),
+            next_off: Self::format_next(&now, app_env.time_off),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = "unknown";
+        writeln!(
+            f,
+            "on:  {} (next: {})",
+            self.time_on,
+            self.next_on.as_deref().unwrap_or(unknown)
+        )?;
+        write!(
+            f,
+            "off: {} (next: {})",
+            self.time_off,
+            self.next_off.as_deref().unwrap_or(unknown)
+        )
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test cron_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cron_schedule_next() {
+        let now = "2025-06-01T12:00:00[Europe/London]"
+            .parse::<Zoned>()
+            .unwrap();
+
+        let result = Schedule::next(&now, Time::constant(13, 30, 0, 0)).unwrap();
+        assert_eq!(
+            result,
+            "2025-06-01T13:30:00[Europe/London]"
+                .parse::<Zoned>()
+                .unwrap()
+        );
+
+        let result = Schedule::next(&now, Time::constant(8, 0, 0, 0)).unwrap();
+        assert_eq!(
+            result,
+            "2025-06-02T08:00:00[Europe/London]"
+                .parse::<Zoned>()
+                .unwrap()
+        );
+
+        let result = Schedule::next(&now, Time::constant(12, 0, 0, 0)).unwrap();
+        assert_eq!(
+            result,
+            "2025-06-02T12:00:00[Europe/London]"
+                .parse::<Zoned>()
+                .unwrap()
+        );
+    }
+}
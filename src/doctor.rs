@@ -0,0 +1,643 @@
+use std::{
+    fmt,
+    io::IsTerminal,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+    app_env::{AppEnv, Endpoint, WsConfig},
+    app_error::AppError,
+    sysinfo::{BUSCTL, DRM_CONNECTORS, SysInfo},
+    ws::Connector,
+};
+
+/// Maximum time to wait for each of the network checks
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Everything the checks depend on, so that each check can be tested with a mocked environment
+trait Probe: Sync {
+    fn load_env(&self) -> Result<AppEnv, AppError>;
+    fn find_executable(&self, name: &str) -> Option<PathBuf>;
+    fn dbus_address(&self) -> String;
+    fn path_exists(&self, path: &Path) -> bool;
+    fn read_to_string(&self, path: &str) -> Option<String>;
+    fn get_auth_token(
+        &self,
+        app_envs: &AppEnv,
+        ws: &WsConfig,
+        endpoint: &Endpoint,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+    fn ws_upgrade(
+        &self,
+        app_envs: &AppEnv,
+        ws: &WsConfig,
+        endpoint: &Endpoint,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+}
+
+/// The real environment, as used by the daemon
+struct SystemProbe;
+
+impl Probe for SystemProbe {
+    fn load_env(&self) -> Result<AppEnv, AppError> {
+        AppEnv::get()
+    }
+
+    /// Search each directory in PATH for an executable file
+    fn find_executable(&self, name: &str) -> Option<PathBuf> {
+        std::env::split_paths(&std::env::var_os("PATH")?)
+            .map(|dir| dir.join(name))
+            .find(|path| {
+                path.metadata()
+                    .is_ok_and(|i| i.is_file() && i.permissions().mode() & 0o111 != 0)
+            })
+    }
+
+    fn dbus_address(&self) -> String {
+        SysInfo::dbus_address()
+    }
+
+    fn path_exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn read_to_string(&self, path: &str) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    async fn get_auth_token(
+        &self,
+        app_envs: &AppEnv,
+        ws: &WsConfig,
+        endpoint: &Endpoint,
+    ) -> Result<(), AppError> {
+        Connector::new(app_envs, ws)?
+            .get_token(endpoint)
+            .await
+            .map(|_| ())
+    }
+
+    async fn ws_upgrade(
+        &self,
+        app_envs: &AppEnv,
+        ws: &WsConfig,
+        endpoint: &Endpoint,
+    ) -> Result<(), AppError> {
+        let mut socket = Connector::new(app_envs, ws)?.ws_upgrade(endpoint).await?;
+        socket.close(None).await.ok();
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pass => write!(f, "PASS"),
+            Self::Fail => write!(f, "FAIL"),
+            Self::Skip => write!(f, "SKIP"),
+        }
+    }
+}
+
+impl CheckStatus {
+    /// The ANSI colour code used when the output is coloured
+    const fn colour(self) -> u8 {
+        match self {
+            Self::Pass => 32,
+            Self::Fail => 31,
+            Self::Skip => 33,
+        }
+    }
+}
+
+/// The result of a single check, the hint is only included on failure
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<&'static str>,
+}
+
+impl Check {
+    const fn pass(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            detail,
+            hint: None,
+        }
+    }
+
+    const fn fail(name: &'static str, detail: String, hint: &'static str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Fail,
+            detail,
+            hint: Some(hint),
+        }
+    }
+
+    fn skip(name: &'static str, reason: &str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Skip,
+            detail: format!("skipped, {reason}"),
+            hint: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub passed: bool,
+    pub checks: Vec<Check>,
+    /// Colour the status of each check, only when stdout is a terminal, and NO_COLOR isn't set
+    #[serde(skip)]
+    pub colour: bool,
+}
+
+impl Report {
+    pub fn failed(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|i| i.status == CheckStatus::Fail)
+            .count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.checks.iter().map(|i| i.name.len()).max().unwrap_or(0);
+        for (index, check) in self.checks.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            if self.colour {
+                write!(
+                    f,
+                    "[\x1b[{}m{}\x1b[0m]",
+                    check.status.colour(),
+                    check.status
+                )?;
+            } else {
+                write!(f, "[{}]", check.status)?;
+            }
+            write!(f, " {:width$}  {}", check.name, check.detail)?;
+            if let Some(hint) = check.hint {
+                write!(f, "\n       {:width$}  hint: {hint}", "")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the envs can be loaded and parsed
+fn check_env(app_envs: &Result<AppEnv, AppError>) -> Check {
+    match app_envs {
+        Ok(_) => Check::pass("env", "envs loaded".to_owned()),
+        Err(e) => Check::fail(
+            "env",
+            e.to_string(),
+            "check ./.env, /app_env/.env, or the service env, the WS envs are optional, but WS_ADDRESS, WS_APIKEY, WS_PASSWORD, unless using a client certificate, and WS_TOKEN_ADDRESS must all be given together",
+        ),
+    }
+}
+
+/// Check busctl, used by toggle_screen, is in PATH
+fn check_busctl<P: Probe>(probe: &P) -> Check {
+    probe.find_executable(BUSCTL).map_or_else(
+        || {
+            Check::fail(
+                "busctl",
+                format!("{BUSCTL} not found in PATH"),
+                "install systemd, which provides busctl, and check PATH",
+            )
+        },
+        |path| Check::pass("busctl", path.display().to_string()),
+    )
+}
+
+/// Check the DBus session bus used by toggle_screen exists
+fn check_dbus<P: Probe>(probe: &P) -> Check {
+    let address = probe.dbus_address();
+    let path = address
+        .strip_prefix("unix:path=")
+        .and_then(|i| i.split(',').next())
+        .unwrap_or(&address);
+    if probe.path_exists(Path::new(path)) {
+        Check::pass("dbus_session", address)
+    } else {
+        Check::fail(
+            "dbus_session",
+            format!("{path} not found"),
+            "run as the user logged into the desktop session, e.g. install with `install --user`, or set DBUS_SESSION_BUS_ADDRESS",
+        )
+    }
+}
+
+/// Check that at least one of the DRM connectors used by screen_status is present
+fn check_drm<P: Probe>(probe: &P) -> Check {
+    DRM_CONNECTORS
+        .iter()
+        .find_map(|path| {
+            probe
+                .read_to_string(path)
+                .map(|value| format!("{path}: {}", value.trim()))
+        })
+        .map_or_else(
+            || {
+                Check::fail(
+                    "drm_connector",
+                    format!("none of {} found", DRM_CONNECTORS.join(", ")),
+                    "check the connected HDMI port, and the connectors listed in /sys/class/drm/",
+                )
+            },
+            |detail| Check::pass("drm_connector", detail),
+        )
+}
+
+/// Run an async network check, with a timeout
+async fn check_network(
+    name: &'static str,
+    check: impl Future<Output = Result<(), AppError>>,
+    pass: String,
+    hint: &'static str,
+) -> Check {
+    match tokio::time::timeout(NETWORK_TIMEOUT, check).await {
+        Ok(Ok(())) => Check::pass(name, pass),
+        Ok(Err(e)) => Check::fail(name, e.to_string(), hint),
+        Err(_) => Check::fail(
+            name,
+            format!("timeout after {}s", NETWORK_TIMEOUT.as_secs()),
+            hint,
+        ),
+    }
+}
+
+/// Run every check, the network checks are skipped if the env is invalid
+async fn diagnose<P: Probe>(probe: &P) -> Report {
+    let app_envs = probe.load_env();
+    let mut checks = vec![
+        check_env(&app_envs),
+        check_busctl(probe),
+        check_dbus(probe),
+        check_drm(probe),
+    ];
+    if let Ok(app_envs) = &app_envs
+        && let Some(ws) = &app_envs.ws
+    {
+        // Every endpoint is checked, so that a broken standby is found before it is needed
+        for endpoint in &ws.endpoints {
+            checks.push(
+                check_network(
+                    "token",
+                    probe.get_auth_token(app_envs, ws, endpoint),
+                    endpoint.token_address.clone(),
+                    "check WS_TOKEN_ADDRESS is reachable, and that WS_APIKEY & WS_PASSWORD are valid",
+                )
+                .await,
+            );
+            checks.push(
+                check_network(
+                    "ws_upgrade",
+                    probe.ws_upgrade(app_envs, ws, endpoint),
+                    endpoint.ws_address.clone(),
+                    "check WS_ADDRESS is reachable, and is a valid ws:// or wss:// URL",
+                )
+                .await,
+            );
+        }
+    } else {
+        let reason = if app_envs.is_ok() {
+            "WS not configured"
+        } else {
+            "env invalid"
+        };
+        checks.push(Check::skip("token", reason));
+        checks.push(Check::skip("ws_upgrade", reason));
+    }
+    Report {
+        passed: checks.iter().all(|i| i.status != CheckStatus::Fail),
+        checks,
+        colour: false,
+    }
+}
+
+/// Check each of the dependencies of the daemon
+pub async fn run() -> Report {
+    let mut report = Box::pin(diagnose(&SystemProbe)).await;
+    report.colour = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    report
+}
+
+/// cargo watch -q -c -w src/ -x 'test doctor_ -- --nocapture'
+#[cfg(test)]
+mod tests {
+    use crate::{S, tests::test_setup};
+
+    use super::*;
+
+    #[derive(Default)]
+    #[expect(clippy::struct_excessive_bools)]
+    struct MockProbe {
+        env_missing: bool,
+        offline: bool,
+        busctl: Option<&'static str>,
+        dbus_exists: bool,
+        drm: Option<&'static str>,
+        token_error: bool,
+        ws_error: bool,
+    }
+
+    impl MockProbe {
+        fn healthy() -> Self {
+            Self {
+                env_missing: false,
+                offline: false,
+                busctl: Some("/usr/bin/busctl"),
+                dbus_exists: true,
+                drm: Some("enabled\n"),
+                token_error: false,
+                ws_error: false,
+            }
+        }
+    }
+
+    impl Probe for MockProbe {
+        fn load_env(&self) -> Result<AppEnv, AppError> {
+            if self.env_missing {
+                Err(AppError::MissingEnv(S!("WS_ADDRESS")))
+            } else {
+                let mut app_envs = test_setup();
+                if self.offline {
+                    app_envs.ws = None;
+                }
+                Ok(app_envs)
+            }
+        }
+
+        fn find_executable(&self, name: &str) -> Option<PathBuf> {
+            assert_eq!(name, BUSCTL);
+            self.busctl.map(PathBuf::from)
+        }
+
+        fn dbus_address(&self) -> String {
+            S!("unix:path=/run/user/1000/bus,guid=abc123")
+        }
+
+        fn path_exists(&self, path: &Path) -> bool {
+            assert_eq!(path, Path::new("/run/user/1000/bus"));
+            self.dbus_exists
+        }
+
+        fn read_to_string(&self, path: &str) -> Option<String> {
+            if path == DRM_CONNECTORS[1] {
+                self.drm.map(|i| S!(i))
+            } else {
+                None
+            }
+        }
+
+        async fn get_auth_token(
+            &self,
+            _: &AppEnv,
+            _: &WsConfig,
+            _: &Endpoint,
+        ) -> Result<(), AppError> {
+            if self.token_error {
+                Err(AppError::WsStatus)
+            } else {
+                Ok(())
+            }
+        }
+
+        async fn ws_upgrade(&self, _: &AppEnv, _: &WsConfig, _: &Endpoint) -> Result<(), AppError> {
+            if self.ws_error {
+                Err(AppError::TungsteniteConnect(S!("connection refused")))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn get_check<'a>(report: &'a Report, name: &str) -> &'a Check {
+        report
+            .checks
+            .iter()
+            .find(|i| i.name == name)
+            .unwrap_or_else(|| unreachable!("this indicates the test has failed"))
+    }
+
+    #[tokio::test]
+    async fn doctor_all_pass() {
+        let report = diagnose(&MockProbe::healthy()).await;
+        assert!(report.passed);
+        assert_eq!(report.failed(), 0);
+        assert_eq!(report.checks.len(), 6);
+        assert!(report.checks.iter().all(|i| i.status == CheckStatus::Pass));
+        assert!(report.checks.iter().all(|i| i.hint.is_none()));
+
+        let drm = get_check(&report, "drm_connector");
+        assert_eq!(drm.detail, format!("{}: enabled", DRM_CONNECTORS[1]));
+    }
+
+    #[tokio::test]
+    async fn doctor_env_invalid() {
+        let probe = MockProbe {
+            env_missing: true,
+            ..MockProbe::healthy()
+        };
+        let report = diagnose(&probe).await;
+        assert!(!report.passed);
+        assert_eq!(report.failed(), 1);
+
+        let env = get_check(&report, "env");
+        assert_eq!(env.status, CheckStatus::Fail);
+        assert_eq!(env.detail, "missing env: 'WS_ADDRESS'");
+        assert!(
+            env.hint
+                .as_ref()
+                .is_some_and(|i| i.contains("the WS envs are optional, but"))
+        );
+
+        assert_eq!(get_check(&report, "token").status, CheckStatus::Skip);
+        assert_eq!(get_check(&report, "ws_upgrade").status, CheckStatus::Skip);
+    }
+
+    #[tokio::test]
+    async fn doctor_offline() {
+        let probe = MockProbe {
+            offline: true,
+            token_error: true,
+            ..MockProbe::healthy()
+        };
+        let report = diagnose(&probe).await;
+        assert!(report.passed);
+        for name in ["token", "ws_upgrade"] {
+            let check = get_check(&report, name);
+            assert_eq!(check.status, CheckStatus::Skip);
+            assert_eq!(check.detail, "skipped, WS not configured");
+        }
+    }
+
+    #[tokio::test]
+    async fn doctor_local_failures() {
+        let report = diagnose(&MockProbe::default()).await;
+        assert!(!report.passed);
+        assert_eq!(report.failed(), 3);
+
+        for name in ["busctl", "dbus_session", "drm_connector"] {
+            let check = get_check(&report, name);
+            assert_eq!(check.status, CheckStatus::Fail);
+            assert!(check.hint.is_some());
+        }
+        assert_eq!(get_check(&report, "env").status, CheckStatus::Pass);
+        assert_eq!(
+            get_check(&report, "dbus_session").detail,
+            "/run/user/1000/bus not found"
+        );
+    }
+
+    #[tokio::test]
+    async fn doctor_network_failures() {
+        let probe = MockProbe {
+            token_error: true,
+            ws_error: true,
+            ..MockProbe::healthy()
+        };
+        let report = diagnose(&probe).await;
+        assert!(!report.passed);
+        assert_eq!(report.failed(), 2);
+
+        let token = get_check(&report, "token");
+        assert_eq!(token.status, CheckStatus::Fail);
+        assert_eq!(token.detail, "Invalid WS Status Code");
+
+        let ws = get_check(&report, "ws_upgrade");
+        assert_eq!(ws.status, CheckStatus::Fail);
+        assert_eq!(ws.detail, "WS Connect: connection refused");
+    }
+
+    #[tokio::test]
+    async fn doctor_endpoints() {
+        struct StandbyProbe;
+
+        impl Probe for StandbyProbe {
+            fn load_env(&self) -> Result<AppEnv, AppError> {
+                let mut app_envs = test_setup();
+                if let Some(ws) = app_envs.ws.as_mut() {
+                    ws.endpoints.push(Endpoint {
+                        ws_address: S!("wss://standby"),
+                        token_address: S!("https://standby/token"),
+                    });
+                }
+                Ok(app_envs)
+            }
+            fn find_executable(&self, name: &str) -> Option<PathBuf> {
+                MockProbe::healthy().find_executable(name)
+            }
+            fn dbus_address(&self) -> String {
+                MockProbe::healthy().dbus_address()
+            }
+            fn path_exists(&self, path: &Path) -> bool {
+                MockProbe::healthy().path_exists(path)
+            }
+            fn read_to_string(&self, path: &str) -> Option<String> {
+                MockProbe::healthy().read_to_string(path)
+            }
+            async fn get_auth_token(
+                &self,
+                _: &AppEnv,
+                _: &WsConfig,
+                _: &Endpoint,
+            ) -> Result<(), AppError> {
+                Ok(())
+            }
+            async fn ws_upgrade(
+                &self,
+                _: &AppEnv,
+                _: &WsConfig,
+                endpoint: &Endpoint,
+            ) -> Result<(), AppError> {
+                if endpoint.ws_address == "wss://standby" {
+                    Err(AppError::TungsteniteConnect(S!("connection refused")))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        let report = diagnose(&StandbyProbe).await;
+        assert!(!report.passed);
+        assert_eq!(report.checks.len(), 8);
+        let details = report
+            .checks
+            .iter()
+            .filter(|i| i.name == "token" || i.name == "ws_upgrade")
+            .map(|i| (i.detail.as_str(), i.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            details,
+            [
+                ("ws_token_address", CheckStatus::Pass),
+                ("ws_address", CheckStatus::Pass),
+                ("https://standby/token", CheckStatus::Pass),
+                ("WS Connect: connection refused", CheckStatus::Fail),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn doctor_report_output() {
+        let probe = MockProbe {
+            busctl: None,
+            ..MockProbe::healthy()
+        };
+        let report = diagnose(&probe).await;
+
+        let json = serde_json::to_value(&report).unwrap_or_default();
+        assert_eq!(json["passed"], false);
+        assert_eq!(json["checks"][1]["name"], "busctl");
+        assert_eq!(json["checks"][1]["status"], "fail");
+        assert!(json["checks"][1]["hint"].is_string());
+        assert!(json["checks"][0].get("hint").is_none());
+
+        let text = report.to_string();
+        assert_eq!(text.lines().count(), 7);
+        assert!(text.contains("[FAIL] busctl"));
+        assert!(text.contains("[PASS] env"));
+        assert!(!text.contains('\x1b'));
+        assert!(text.contains("busctl not found in PATH"));
+        assert!(text.contains("hint: install systemd"));
+
+        let mut report = report;
+        report.colour = true;
+        let text = report.to_string();
+        assert!(text.contains("[\x1b[31mFAIL\x1b[0m] busctl"));
+        assert!(text.contains("[\x1b[32mPASS\x1b[0m] env"));
+    }
+
+    #[test]
+    fn doctor_find_executable() {
+        assert!(SystemProbe.find_executable("sh").is_some());
+        assert!(
+            SystemProbe
+                .find_executable("not_a_real_executable")
+                .is_none()
+        );
+    }
+}
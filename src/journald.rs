@@ -0,0 +1,226 @@
+use std::{fmt, os::unix::net::UnixDatagram, path::Path};
+
+use tracing::{
+    Event, Level, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{Layer, layer::Context};
+
+use crate::app_error::AppError;
+
+/// The default location of the journald native protocol socket
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+const APP_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Prefix of each event field, e.g. `event = "screen_off"` can be filtered with `journalctl SCREEN_CONTROL_EVENT=screen_off`
+const FIELD_PREFIX: &str = "SCREEN_CONTROL_";
+
+/// A tracing layer which writes each event, and its fields, directly to journald using the native protocol
+#[derive(Debug)]
+pub struct JournaldLayer {
+    socket: UnixDatagram,
+}
+
+impl JournaldLayer {
+    /// Connect to the journald socket, will error if journald isn't running
+    pub fn new(path: &Path) -> Result<Self, AppError> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self { socket })
+    }
+
+    /// Syslog priority of each level
+    const fn priority(level: Level) -> &'static str {
+        match level {
+            Level::ERROR => "3",
+            Level::WARN => "4",
+            Level::INFO => "6",
+            Level::DEBUG | Level::TRACE => "7",
+        }
+    }
+}
+
+/// Append a field in the journald native format, values containing a newline are length prefixed
+fn put_field(payload: &mut Vec<u8>, name: &str, value: &str) {
+    payload.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        payload.push(b'\n');
+        payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        payload.push(b'=');
+    }
+    payload.extend_from_slice(value.as_bytes());
+    payload.push(b'\n');
+}
+
+/// Journald field names may only contain uppercase letters, digits, and underscores
+fn field_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!("{FIELD_PREFIX}{name}")
+}
+
+/// Collect the message, and each field, of an event
+#[derive(Debug, Default)]
+struct JournaldVisitor {
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for JournaldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            value.clone_into(&mut self.message);
+        } else {
+            self.fields
+                .push((field_name(field.name()), value.to_owned()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        } else {
+            self.fields
+                .push((field_name(field.name()), format!("{value:?}")));
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for JournaldLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut visitor = JournaldVisitor::default();
+        event.record(&mut visitor);
+
+        let mut payload = vec![];
+        put_field(&mut payload, "MESSAGE", &visitor.message);
+        put_field(&mut payload, "PRIORITY", Self::priority(*metadata.level()));
+        put_field(&mut payload, "SYSLOG_IDENTIFIER", APP_NAME);
+        put_field(&mut payload, &field_name("target"), metadata.target());
+        if let Some(file) = metadata.file() {
+            put_field(&mut payload, "CODE_FILE", file);
+        }
+        if let Some(line) = metadata.line() {
+            put_field(&mut payload, "CODE_LINE", &line.to_string());
+        }
+        for (name, value) in &visitor.fields {
+            put_field(&mut payload, name, value);
+        }
+        // Nowhere to log a failure to log, so the event is dropped
+        self.socket.send(&payload).ok();
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test journald_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::S;
+
+    fn test_socket(name: &str) -> (PathBuf, UnixDatagram) {
+        let path = std::env::temp_dir().join(format!(
+            "screen_control_test_journald_{name}_{}.sock",
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        (path, socket)
+    }
+
+    fn recv(socket: &UnixDatagram) -> Vec<u8> {
+        let mut buf = vec![0; 4096];
+        let len = socket.recv(&mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    /// Split a payload, without any multi-line values, into its fields
+    fn fields(payload: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(payload)
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn journald_field_name() {
+        assert_eq!(field_name("event"), "SCREEN_CONTROL_EVENT");
+        assert_eq!(field_name("ws_state"), "SCREEN_CONTROL_WS_STATE");
+        assert_eq!(field_name("log.target"), "SCREEN_CONTROL_LOG_TARGET");
+    }
+
+    #[test]
+    fn journald_put_field() {
+        let mut payload = vec![];
+        put_field(&mut payload, "MESSAGE", "screen off");
+        assert_eq!(payload, b"MESSAGE=screen off\n");
+
+        let mut payload = vec![];
+        put_field(&mut payload, "MESSAGE", "screen\noff");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&10u64.to_le_bytes());
+        expected.extend_from_slice(b"screen\noff\n");
+        assert_eq!(payload, expected);
+    }
+
+    #[test]
+    fn journald_layer_event() {
+        let (path, socket) = test_socket("event");
+        let layer = JournaldLayer::new(&path).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(event = "screen_off", source = "schedule", "screen off");
+            tracing::error!(event = "status", unique = "abc-123", "failed: {}", 42);
+        });
+
+        let result = fields(&recv(&socket));
+        for expected in [
+            "MESSAGE=screen off",
+            "PRIORITY=6",
+            "SYSLOG_IDENTIFIER=screen_control",
+            "SCREEN_CONTROL_TARGET=screen_control::journald::tests",
+            "SCREEN_CONTROL_EVENT=screen_off",
+            "SCREEN_CONTROL_SOURCE=schedule",
+        ] {
+            assert!(result.contains(&S!(expected)), "{expected} missing");
+        }
+        assert!(result.iter().any(|i| i.starts_with("CODE_LINE=")));
+
+        let result = fields(&recv(&socket));
+        for expected in [
+            "MESSAGE=failed: 42",
+            "PRIORITY=3",
+            "SCREEN_CONTROL_EVENT=status",
+            "SCREEN_CONTROL_UNIQUE=abc-123",
+        ] {
+            assert!(result.contains(&S!(expected)), "{expected} missing");
+        }
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn journald_no_socket() {
+        let (path, _) = test_socket("no_socket");
+        std::fs::remove_file(&path).unwrap();
+        assert!(JournaldLayer::new(&path).is_err());
+    }
+}
@@ -6,8 +6,13 @@ static GLOBAL: MiMalloc = MiMalloc;
 
 mod app_env;
 mod app_error;
+mod cli;
+mod control;
 mod cron;
+mod doctor;
+mod journald;
 mod message_handler;
+mod sd_notify;
 mod sysinfo;
 mod systemd;
 mod ws;
@@ -15,13 +20,22 @@ mod ws_messages;
 
 use app_env::AppEnv;
 use app_error::AppError;
-use cron::Croner;
+use cli::{Cli, CliCommand, Done, ScreenChange, print_output};
+use control::{ControlClient, ControlServer};
+use cron::{Croner, Schedule};
+use journald::JournaldLayer;
 use simple_signal::Signal;
-use std::env::Args;
+use std::{process::ExitCode, time::Duration};
 use sysinfo::SysInfo;
-use systemd::configure_systemd;
+use systemd::{Scope, UnitOptions};
+use tracing_subscriber::{
+    Layer, filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt,
+};
 
-use crate::message_handler::Msg;
+use crate::{
+    message_handler::Msg,
+    ws_messages::{ParsedMessage, PiStatus, Response, ScreenBody, ScreenStatus},
+};
 
 /// Simple macro to create a new String, or convert from a &str to  a String - basically just gets rid of String::from() / .to_owned() etc
 #[macro_export]
@@ -63,122 +77,233 @@ fn close_signal(tx: &Sender<Msg>) {
     });
 }
 
+/// Daemon logs go to stdout, or directly to journald if configured, all other commands log to stderr, so as to not interfere with any --json output
 fn setup_tracing(app_envs: Option<&AppEnv>) {
-    tracing_subscriber::fmt()
-        .with_max_level(app_envs.map_or(tracing::Level::DEBUG, |i| i.log_level))
-        .init();
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-enum CliArg {
-    On,
-    Off,
-    Install,
-    Uninstall,
-    Help,
-}
-
-/// display cli argument information
-fn display_arg_info() {
-    println!(
-        "
-{} v{}
-
- --on  Turn screen on
---off  Turn screen off
-   -i  Install systemd service, requires running as SUDO
-   -u  Uninstall systemd service, requires running as SUDO
-   -h  Display this Help section
-",
-        env!("CARGO_PKG_NAME"),
-        env!("CARGO_PKG_VERSION")
-    );
-}
-/// Parse the command line arguments
-fn parse_arg(args: Args) -> Option<CliArg> {
-    match args.skip(1).take(1).collect::<String>().trim() {
-        "-i" => Some(CliArg::Install),
-        "-u" => Some(CliArg::Uninstall),
-        "--on" => Some(CliArg::On),
-        "--off" => Some(CliArg::Off),
-        "-h" => Some(CliArg::Help),
-        _ => None,
+    if let Some(app_envs) = app_envs {
+        match app_envs.log_journald.as_deref().map(JournaldLayer::new) {
+            Some(Ok(layer)) => {
+                tracing_subscriber::registry()
+                    .with(layer.with_filter(LevelFilter::from_level(app_envs.log_level)))
+                    .init();
+            }
+            journald => {
+                tracing_subscriber::fmt()
+                    .with_max_level(app_envs.log_level)
+                    .init();
+                if let Some(Err(e)) = journald {
+                    tracing::error!("unable to log to journald, {e}");
+                }
+            }
+        }
+    } else {
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_writer(std::io::stderr)
+            .init();
     }
 }
 
 /// Run the client, connect to WS as long running process
 async fn run_as_client() -> Result<(), AppError> {
-    let app_envs = AppEnv::get();
+    let app_envs = AppEnv::get()?;
     setup_tracing(Some(&app_envs));
     let (tx, rx) = async_channel::bounded(2048);
     close_signal(&tx);
     Croner::start(&app_envs, &tx);
-    message_handler::MessageHandler::new(app_envs, rx, tx)
+    if let Err(e) = ControlServer::start(&control::socket_path(), &tx).await {
+        tracing::error!("{e}");
+    }
+    message_handler::MessageHandler::new(&app_envs, rx, tx)?
         .start()
         .await
 }
 
-// if want to change, need to reload service?
-async fn start() -> Result<(), AppError> {
-    if let Some(arg) = parse_arg(std::env::args()) {
-        match arg {
-            CliArg::Install | CliArg::Uninstall => {
-                setup_tracing(None);
-                if let Err(e) = configure_systemd(arg) {
-                    tracing::error!("{e:?}");
-                }
+/// Set the screen status via the running daemon, or directly if the daemon isn't running, and print the result.
+/// A duration requires the daemon, as it is responsible for reverting the change
+async fn set_screen(
+    json: bool,
+    screen_status: ScreenStatus,
+    duration: Option<Duration>,
+) -> Result<(), AppError> {
+    let body = duration.map(ScreenBody::from);
+    let message = match screen_status {
+        ScreenStatus::On => ParsedMessage::ScreenOn(body),
+        ScreenStatus::Off => ParsedMessage::ScreenOff(body),
+    };
+    let (daemon, revert) =
+        match ControlClient::send(&control::daemon_socket_path(), message).await? {
+            Some(Response::Error(e)) => return Err(AppError::ScreenCommand(e)),
+            Some(Response::Status(status)) => (true, status.revert),
+            Some(Response::ScreenChange(_)) => {
+                return Err(AppError::ControlSocket(S!(
+                    "unexpected response from daemon"
+                )));
             }
-            CliArg::On => {
-                setup_tracing(None);
-                if let Err(e) = SysInfo::turn_on().await {
-                    tracing::error!("{e:?}");
+            None => {
+                if duration.is_some() {
+                    return Err(AppError::DaemonRequired);
                 }
+                SysInfo::toggle_screen(&screen_status).await?;
+                (false, None)
             }
-            CliArg::Off => {
-                setup_tracing(None);
-                if let Err(e) = SysInfo::turn_off().await {
-                    tracing::error!("{e:?}");
-                }
+        };
+    print_output(
+        json,
+        &ScreenChange {
+            screen_status,
+            daemon,
+            revert,
+        },
+    );
+    Ok(())
+}
+
+/// Get the status from the running daemon, or generate locally if the daemon isn't running
+async fn get_status() -> Result<PiStatus, AppError> {
+    match ControlClient::send(&control::daemon_socket_path(), ParsedMessage::Status).await? {
+        Some(Response::Status(status)) => Ok(*status),
+        Some(Response::Error(e)) => Err(AppError::ControlSocket(e)),
+        Some(Response::ScreenChange(_)) => Err(AppError::ControlSocket(S!(
+            "unexpected response from daemon"
+        ))),
+        None => {
+            let app_envs = AppEnv::get()?;
+            Ok(PiStatus::new(
+                SysInfo::new(&app_envs).await,
+                0,
+                None,
+                None,
+                None,
+            ))
+        }
+    }
+}
+
+/// Execute the given cli command, no command means run as a long running process
+async fn start(cli: Cli) -> Result<(), AppError> {
+    let command = cli.command.unwrap_or(CliCommand::Run);
+    if !matches!(command, CliCommand::Run) {
+        setup_tracing(None);
+    }
+    match command {
+        CliCommand::Run => run_as_client().await?,
+        CliCommand::On { duration } => set_screen(cli.json, ScreenStatus::On, duration).await?,
+        CliCommand::Off { duration } => set_screen(cli.json, ScreenStatus::Off, duration).await?,
+        CliCommand::Toggle { duration } => {
+            let screen_status = SysInfo::screen_status()
+                .await
+                .ok_or(AppError::ScreenStatusUnknown)?
+                .toggle();
+            set_screen(cli.json, screen_status, duration).await?;
+        }
+        CliCommand::Status => print_output(cli.json, &get_status().await?),
+        CliCommand::Install(args) => {
+            let options = UnitOptions::new(&args)?;
+            if args.dry_run {
+                print_output(cli.json, &systemd::dry_run(&options)?);
+            } else {
+                systemd::install(&options)?;
+                print_output(cli.json, &Done { command: "install" });
+            }
+        }
+        CliCommand::Uninstall { user } => {
+            systemd::uninstall(Scope::from_user_flag(user))?;
+            print_output(
+                cli.json,
+                &Done {
+                    command: "uninstall",
+                },
+            );
+        }
+        CliCommand::Schedule => {
+            let app_envs = AppEnv::get()?;
+            print_output(cli.json, &Schedule::new(&app_envs));
+        }
+        CliCommand::Doctor => {
+            let report = Box::pin(doctor::run()).await;
+            print_output(cli.json, &report);
+            if !report.passed {
+                return Err(AppError::DoctorFailed(report.failed()));
             }
-            CliArg::Help => display_arg_info(),
         }
-    } else {
-        run_as_client().await?;
     }
     Ok(())
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    let cli = Cli::get();
     tokio::spawn(async move {
-        if let Err(e) = start().await {
-            tracing::error!("{e}");
+        let json = cli.json;
+        match Box::pin(start(cli)).await {
+            Ok(()) => ExitCode::SUCCESS,
+            // The report has already been printed
+            Err(e @ AppError::DoctorFailed(_)) => ExitCode::from(e.exit_code()),
+            Err(e) => cli::print_error(json, &e),
         }
     })
     .await
-    .ok();
+    .unwrap_or(ExitCode::FAILURE)
 }
 
 // check the status of the screen power
 
 #[cfg(test)]
 mod tests {
-    use std::time::SystemTime;
+    use std::time::{Duration, SystemTime};
 
     use jiff::civil::Time;
 
-    use crate::app_env::AppEnv;
+    use crate::{
+        app_env::{
+            AppEnv, BackoffConfig, Endpoint, FailoverConfig, HeartbeatConfig, OutboxConfig,
+            TimeoutConfig, WsAuth, WsConfig,
+        },
+        ws::{ProxyConfig, TlsConfig},
+    };
 
     pub fn test_setup() -> AppEnv {
         AppEnv {
+            backoff: BackoffConfig {
+                initial: Duration::from_secs(1),
+                max: Duration::from_secs(60),
+                reset_after: Duration::from_secs(30),
+            },
+            failover: FailoverConfig {
+                probe_interval: Duration::from_secs(30),
+                failback_after: Duration::from_secs(300),
+            },
+            heartbeat: HeartbeatConfig {
+                interval: Some(Duration::from_secs(15)),
+                missed: 3,
+                auto_close: Some(Duration::from_secs(40)),
+            },
             log_level: tracing::Level::INFO,
+            log_journald: None,
+            outbox: OutboxConfig {
+                size: 100,
+                path: None,
+            },
+            proxy: ProxyConfig::default(),
             start_time: SystemTime::now(),
-            ws_address: S!("ws_address"),
-            ws_apikey: S!("ws_apikey"),
-            ws_password: S!("ws_password"),
+            timeouts: TimeoutConfig {
+                token: Duration::from_secs(10),
+                connect: Duration::from_secs(10),
+                upgrade: Duration::from_secs(10),
+            },
+            tls: TlsConfig::default(),
             time_on: Time::constant(8, 0, 0, 0),
             time_off: Time::constant(9, 0, 0, 0),
-            ws_token_address: S!("ws_token_address"),
+            ws: Some(WsConfig {
+                apikey: S!("ws_apikey"),
+                auth: WsAuth::Path,
+                endpoints: vec![Endpoint {
+                    ws_address: S!("ws_address"),
+                    token_address: S!("ws_token_address"),
+                }],
+                password: Some(S!("ws_password")),
+            }),
+            ws_token_ttl: Duration::from_secs(60),
         }
     }
 }
@@ -1,15 +1,23 @@
+use std::{fmt, time::Duration};
+
 use async_channel::{Receiver, Sender};
 use tokio::net::TcpStream;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     C,
-    app_env::AppEnv,
+    app_env::{AppEnv, HeartbeatConfig},
     app_error::AppError,
+    control::{self, ControlServer},
+    sd_notify::SdNotify,
     sleep,
     sysinfo::SysInfo,
-    ws::{ConnectionDetails, Socket, WSSender, open_connection},
-    ws_messages::Response,
+    ws::{ConnectionManager, ConnectionMsg, Outbox, Socket, WSSender},
+    ws_messages::{
+        ConnectionStatus, ParsedMessage, PendingRevert, Response, Rtt, ScreenBody, ScreenEvent,
+        ScreenStatus, StructuredResponse,
+    },
 };
 
 pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
@@ -22,46 +30,248 @@ pub type WSWriter = futures_util::stream::SplitSink<
 
 #[derive(Debug)]
 pub enum Msg {
+    Connection(ConnectionStatus),
+    Control(ParsedMessage, Sender<Response>),
     Exit,
+    Heartbeat,
     Ping,
+    Pong(Vec<u8>),
     Received(String),
-    ScreenOn,
+    Revert(ScreenStatus),
+    ScreenOn(Option<Duration>, Source),
     Status,
-    ScreenOff,
+    ScreenOff(Option<Duration>, Source),
     ToSend(Response),
+    Watchdog,
     WsClose,
     WsConnected(Box<WsStream>),
 }
 
+/// What requested a screen change, included as a field in the logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Cli,
+    Revert,
+    Schedule,
+    Ws,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self {
+            Self::Cli => "cli",
+            Self::Revert => "revert",
+            Self::Schedule => "schedule",
+            Self::Ws => "ws",
+        };
+        write!(f, "{source}")
+    }
+}
+
 #[derive(Debug)]
 pub struct MessageHandler {
-    app_env: AppEnv,
     rx: Receiver<Msg>,
-    connection_details: ConnectionDetails,
+    connection: Option<ConnectionStatus>,
+    connection_manager: Option<ConnectionManager>,
+    connection_tx: Option<Sender<ConnectionMsg>>,
+    heartbeat: HeartbeatConfig,
+    notify: SdNotify,
+    outbox: Outbox,
+    revert: Option<(PendingRevert, CancellationToken)>,
     socket: Option<Socket>,
     tx: Sender<Msg>,
     ws_sender: WSSender,
 }
 
 impl MessageHandler {
-    /// Send a status update, will be spawned in own thread before sending back to message handler here
+    /// Send a status update, will be spawned in own thread before sending back to message handler here.
+    /// There's nowhere to send it when running offline
     fn send_status(&self, ms: Option<u64>) {
-        let ws = C!(self.ws_sender);
+        if self.connection.is_none() {
+            return;
+        }
+        let (ws, revert, connection, rtt) = (
+            C!(self.ws_sender),
+            self.pending_revert(),
+            C!(self.connection),
+            self.rtt(),
+        );
         tokio::spawn(async move {
             if let Some(ms) = ms {
                 sleep!(ms);
             }
-            ws.send_status().await;
+            ws.send_status(revert, connection, rtt).await;
+        });
+    }
+
+    fn rtt(&self) -> Option<Rtt> {
+        self.socket.as_ref().and_then(Socket::rtt)
+    }
+
+    fn pending_revert(&self) -> Option<PendingRevert> {
+        self.revert.as_ref().map(|(pending, _)| C!(pending))
+    }
+
+    /// Cancel the revert of a timed screen change, if one is pending
+    fn cancel_revert(&mut self) {
+        if let Some((_, token)) = self.revert.take() {
+            token.cancel();
+        }
+    }
+
+    /// Spawn a task to set the screen to `screen_status` after the given duration, will be cancelled by any other screen change
+    fn schedule_revert(&mut self, screen_status: ScreenStatus, duration: Duration) {
+        let token = CancellationToken::new();
+        let (tx, t_token) = (C!(self.tx), C!(token));
+        let msg = Msg::Revert(C!(screen_status));
+        tokio::spawn(async move {
+            t_token
+                .run_until_cancelled(async move {
+                    tokio::time::sleep(duration).await;
+                    tx.send(msg).await.ok();
+                })
+                .await;
         });
+        let at = jiff::Timestamp::now()
+            .as_second()
+            .saturating_add(i64::try_from(duration.as_secs()).unwrap_or(i64::MAX));
+        tracing::info!(
+            "screen will revert to {screen_status} in {}s",
+            duration.as_secs()
+        );
+        self.revert = Some((PendingRevert { screen_status, at }, token));
+    }
+
+    /// Set the screen status, and then send a status update.
+    /// Any pending revert is cancelled, and if a duration is given then a revert to the previous status is scheduled
+    async fn set_screen(
+        &mut self,
+        status: &ScreenStatus,
+        duration: Option<Duration>,
+        source: Source,
+    ) -> Result<(), AppError> {
+        tracing::info!(
+            event = format!("screen_{status}"),
+            source = source.to_string(),
+            "screen {status}"
+        );
+        self.cancel_revert();
+        let previous = if duration.is_some() {
+            SysInfo::screen_status().await
+        } else {
+            None
+        };
+        let result = SysInfo::toggle_screen(status).await;
+        if result.is_ok() {
+            if let Some(duration) = duration {
+                self.schedule_revert(previous.unwrap_or_else(|| status.toggle()), duration);
+            }
+            // Each change is sent, or queued, as an event, as the status is only a snapshot
+            if self.connection.is_some() {
+                self.send_or_queue(Response::ScreenChange(ScreenEvent {
+                    screen_status: C!(status),
+                    source: source.to_string(),
+                }))
+                .await;
+            }
+        }
+        self.send_status(Some(250));
+        result
     }
 
-    /// Start the message handler
+    /// Handle a message from the control socket, reply with either the current status, or an error
+    async fn on_control(&mut self, message: ParsedMessage, reply: Sender<Response>) {
+        let result = match message {
+            ParsedMessage::Status => Ok(None),
+            ParsedMessage::ScreenOn(body) => self
+                .set_screen(&ScreenStatus::On, ScreenBody::duration(body), Source::Cli)
+                .await
+                .map(|()| Some(250)),
+            ParsedMessage::ScreenOff(body) => self
+                .set_screen(&ScreenStatus::Off, ScreenBody::duration(body), Source::Cli)
+                .await
+                .map(|()| Some(250)),
+        };
+        match result {
+            Ok(ms) => {
+                let (ws, revert, connection, rtt) = (
+                    C!(self.ws_sender),
+                    self.pending_revert(),
+                    C!(self.connection),
+                    self.rtt(),
+                );
+                tokio::spawn(async move {
+                    if let Some(ms) = ms {
+                        sleep!(ms);
+                    }
+                    reply
+                        .send(Response::Status(Box::new(
+                            ws.status(revert, connection, rtt).await,
+                        )))
+                        .await
+                        .ok();
+                });
+            }
+            Err(e) => {
+                tracing::error!("{e}");
+                reply.send(Response::Error(e.to_string())).await.ok();
+            }
+        }
+    }
+
+    /// Close the socket, and tell the connection manager to reconnect.
+    /// Both the reader, and the autocloser, can send a close, only the first is passed on
+    async fn close_socket(&mut self) {
+        if let Some(mut socket) = self.socket.take() {
+            socket.close().await;
+            if let Some(connection_tx) = &self.connection_tx {
+                connection_tx.try_send(ConnectionMsg::Closed).ok();
+            }
+        }
+    }
+
+    /// Send a response, queued in the outbox if it can't be sent
+    async fn send_or_queue(&mut self, response: Response) {
+        let sent = match &mut self.socket {
+            Some(socket) => socket.send(StructuredResponse::data(C!(response))).await,
+            None => false,
+        };
+        if !sent {
+            self.outbox.push(response).await;
+        }
+    }
+
+    /// Send, in order, the responses queued whilst disconnected, any that can't be sent remain queued
+    async fn flush_outbox(&mut self) {
+        let Some(socket) = &mut self.socket else {
+            return;
+        };
+        if self.outbox.len() == 0 {
+            return;
+        }
+        let sent = self.outbox.flush(socket).await;
+        tracing::info!(sent, queued = self.outbox.len(), "outbox flushed");
+    }
+
+    /// Start the message handler, systemd is notified that the daemon is ready before the connection manager is started.
+    /// Connecting, and reconnecting, happen in the connection manager task, so screen changes are handled throughout an outage
     pub async fn start(&mut self) -> Result<(), AppError> {
-        open_connection(&self.app_env, &self.tx, &mut self.connection_details).await;
+        self.notify.ready();
+        self.notify.start_watchdog(&self.tx);
+        if let Some(connection_manager) = self.connection_manager.take() {
+            connection_manager.start();
+        } else {
+            tracing::info!("WS not configured, running offline");
+            self.notify.status("running offline, WS not configured");
+        }
 
         while let Ok(msg) = self.rx.recv().await {
             match msg {
+                Msg::Connection(status) => self.connection = Some(status),
+                Msg::Control(message, reply) => self.on_control(message, reply).await,
                 Msg::Exit => {
+                    self.notify.stopping();
+                    ControlServer::remove(&control::socket_path());
                     if let Some(socket) = &mut self.socket {
                         socket.close().await;
                     }
@@ -69,45 +279,53 @@ impl MessageHandler {
                 Msg::Status => {
                     self.send_status(None);
                 }
+                Msg::Heartbeat => {
+                    if let Some(socket) = &mut self.socket
+                        && !socket.ping().await
+                    {
+                        self.close_socket().await;
+                    }
+                }
                 Msg::Ping => {
                     if let Some(socket) = &mut self.socket {
                         socket.on_ping(&self.tx);
                     }
                 }
+                Msg::Pong(payload) => {
+                    if let Some(socket) = &mut self.socket {
+                        socket.on_pong(&payload, &self.tx);
+                    }
+                }
                 Msg::Received(msg) => {
                     let ws_sender = C!(self.ws_sender);
                     tokio::spawn(async move {
                         ws_sender.on_text(msg).await;
                     });
                 }
-                Msg::ScreenOn => {
-                    if let Err(e) = SysInfo::turn_on().await {
+                Msg::Revert(status) => {
+                    if let Err(e) = self.set_screen(&status, None, Source::Revert).await {
                         tracing::error!("{e}");
-                        // TODO Send an error message to the unique client
                     }
-                    self.send_status(Some(250));
                 }
-                Msg::ScreenOff => {
-                    if let Err(e) = SysInfo::turn_off().await {
-                        // TODO Send an error message to the unique client
+                Msg::ScreenOn(duration, source) => {
+                    if let Err(e) = self.set_screen(&ScreenStatus::On, duration, source).await {
                         tracing::error!("{e}");
-                    }
-                    self.send_status(Some(250));
-                }
-                Msg::ToSend(response) => {
-                    if let Some(socket) = &mut self.socket {
-                        socket.send(response).await;
+                        // TODO Send an error message to the unique client
                     }
                 }
-                Msg::WsClose => {
-                    if let Some(socket) = &mut self.socket {
-                        socket.close().await;
+                Msg::ScreenOff(duration, source) => {
+                    if let Err(e) = self.set_screen(&ScreenStatus::Off, duration, source).await {
+                        // TODO Send an error message to the unique client
+                        tracing::error!("{e}");
                     }
-                    open_connection(&self.app_env, &self.tx, &mut self.connection_details).await;
-                    self.ws_sender.on_connection();
                 }
+                Msg::ToSend(response) => self.send_or_queue(response).await,
+                Msg::Watchdog => self.notify.watchdog(),
+                Msg::WsClose => self.close_socket().await,
                 Msg::WsConnected(stream) => {
-                    self.socket = Some(Socket::new(stream, &self.tx));
+                    self.socket = Some(Socket::new(stream, &self.tx, self.heartbeat));
+                    self.ws_sender.on_connection();
+                    self.flush_outbox().await;
                     self.send_status(None);
                 }
             }
@@ -115,16 +333,30 @@ impl MessageHandler {
         Ok(())
     }
 
-    pub fn new(app_env: AppEnv, rx: Receiver<Msg>, tx: Sender<Msg>) -> Self {
-        let ws_sender = WSSender::new(&app_env, &tx);
+    pub fn new(app_env: &AppEnv, rx: Receiver<Msg>, tx: Sender<Msg>) -> Result<Self, AppError> {
+        let ws_sender = WSSender::new(app_env, &tx);
+        let notify = SdNotify::from_env();
+        let (connection_manager, connection_tx) = match &app_env.ws {
+            Some(ws) => {
+                let (connection_manager, connection_tx) =
+                    ConnectionManager::new(app_env, ws, &tx, &notify)?;
+                (Some(connection_manager), Some(connection_tx))
+            }
+            None => (None, None),
+        };
 
-        Self {
-            app_env,
-            connection_details: ConnectionDetails::new(),
+        Ok(Self {
+            connection: app_env.ws.as_ref().map(|_| ConnectionStatus::default()),
+            connection_manager,
+            connection_tx,
+            heartbeat: app_env.heartbeat,
+            notify,
+            outbox: Outbox::new(C!(app_env.outbox)),
+            revert: None,
             rx,
             socket: None,
             tx,
             ws_sender,
-        }
+        })
     }
 }
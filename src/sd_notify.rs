@@ -0,0 +1,213 @@
+use std::{
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    time::Duration,
+};
+
+use async_channel::Sender;
+
+use crate::{C, message_handler::Msg};
+
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID: &str = "WATCHDOG_PID";
+
+/// The systemd service notification protocol, datagrams of `KEY=VALUE` lines sent to `$NOTIFY_SOCKET`, without a libsystemd dependency.
+/// When not started by systemd, with `Type=notify`, every notification is a no-op
+#[derive(Debug, Clone)]
+pub struct SdNotify {
+    socket: Option<SocketAddr>,
+    watchdog: Option<Duration>,
+}
+
+impl SdNotify {
+    /// Parse the socket address, an `@` prefix indicates a Linux abstract socket
+    fn parse_socket(value: &str) -> Option<SocketAddr> {
+        match value.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()).ok(),
+            None if value.starts_with('/') => SocketAddr::from_pathname(value).ok(),
+            None => None,
+        }
+    }
+
+    /// The watchdog is only enabled if WATCHDOG_USEC is a positive number, and WATCHDOG_PID, if set, is this process
+    fn parse_watchdog(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+        if let Some(pid) = pid
+            && pid.parse::<u32>().ok() != Some(std::process::id())
+        {
+            return None;
+        }
+        usec.and_then(|i| i.parse::<u64>().ok())
+            .filter(|i| *i > 0)
+            .map(Duration::from_micros)
+    }
+
+    fn new(socket: Option<&str>, watchdog_usec: Option<&str>, watchdog_pid: Option<&str>) -> Self {
+        let socket = socket.and_then(Self::parse_socket);
+        Self {
+            watchdog: socket
+                .as_ref()
+                .and_then(|_| Self::parse_watchdog(watchdog_usec, watchdog_pid)),
+            socket,
+        }
+    }
+
+    /// Read the socket, and watchdog interval, from the environment set by systemd
+    pub fn from_env() -> Self {
+        let var = |key| std::env::var(key).ok();
+        Self::new(
+            var(NOTIFY_SOCKET).as_deref(),
+            var(WATCHDOG_USEC).as_deref(),
+            var(WATCHDOG_PID).as_deref(),
+        )
+    }
+
+    /// Send a notification, failures are logged, but are otherwise ignored
+    fn notify(&self, state: &str) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        if let Err(e) =
+            UnixDatagram::unbound().and_then(|i| i.send_to_addr(state.as_bytes(), socket))
+        {
+            tracing::debug!("sd_notify: {e}");
+        }
+    }
+
+    /// Start up is complete, the daemon is running
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    /// Free form status, displayed by `systemctl status`
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status.replace('\n', " ")));
+    }
+
+    /// The daemon is shutting down
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Keep-alive ping, the service is restarted if these stop arriving within WatchdogSec
+    pub fn watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+
+    /// Spawn a ticker which sends `Msg::Watchdog` at half the watchdog interval, the ping is sent by the message handler,
+    /// so that a blocked message handler results in a restart
+    pub fn start_watchdog(&self, tx: &Sender<Msg>) {
+        let Some(interval) = self.watchdog else {
+            return;
+        };
+        let tx = C!(tx);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval / 2);
+            loop {
+                interval.tick().await;
+                if tx.send(Msg::Watchdog).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test sd_notify_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn test_socket(name: &str) -> (PathBuf, UnixDatagram) {
+        let path = std::env::temp_dir().join(format!(
+            "screen_control_test_notify_{name}_{}.sock",
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        (path, socket)
+    }
+
+    fn recv(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).to_string()
+    }
+
+    #[test]
+    fn sd_notify_send() {
+        let (path, socket) = test_socket("send");
+        let notify = SdNotify::new(path.to_str(), None, None);
+
+        notify.ready();
+        assert_eq!(recv(&socket), "READY=1");
+        notify.status("connected\nto server");
+        assert_eq!(recv(&socket), "STATUS=connected to server");
+        notify.watchdog();
+        assert_eq!(recv(&socket), "WATCHDOG=1");
+        notify.stopping();
+        assert_eq!(recv(&socket), "STOPPING=1");
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn sd_notify_abstract() {
+        let name = format!("screen_control_test_notify_{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        SdNotify::new(Some(&format!("@{name}")), None, None).ready();
+        assert_eq!(recv(&socket), "READY=1");
+    }
+
+    #[test]
+    fn sd_notify_no_socket() {
+        let notify = SdNotify::new(None, Some("30000000"), None);
+        assert!(notify.socket.is_none());
+        assert!(notify.watchdog.is_none());
+        notify.ready();
+
+        let notify = SdNotify::new(Some("relative/path"), None, None);
+        assert!(notify.socket.is_none());
+    }
+
+    #[test]
+    fn sd_notify_watchdog() {
+        let pid = std::process::id().to_string();
+        let socket = Some("/run/systemd/notify");
+
+        let notify = SdNotify::new(socket, Some("30000000"), None);
+        assert_eq!(notify.watchdog, Some(Duration::from_secs(30)));
+
+        let notify = SdNotify::new(socket, Some("30000000"), Some(&pid));
+        assert_eq!(notify.watchdog, Some(Duration::from_secs(30)));
+
+        let notify = SdNotify::new(socket, Some("30000000"), Some("1"));
+        assert!(notify.watchdog.is_none());
+
+        for invalid in ["0", "-1", "fish"] {
+            let notify = SdNotify::new(socket, Some(invalid), None);
+            assert!(notify.watchdog.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn sd_notify_watchdog_ticker() {
+        let notify = SdNotify::new(Some("/run/systemd/notify"), Some("20000"), None);
+        let (tx, rx) = async_channel::bounded(16);
+        notify.start_watchdog(&tx);
+        let msg = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+        assert!(matches!(msg, Ok(Ok(Msg::Watchdog))));
+    }
+}
@@ -1,11 +1,18 @@
-use std::process::Output;
-
 use local_ip_address::local_ip;
 use serde::{Deserialize, Serialize};
 use tokio::fs::read_to_string;
 
 use crate::{S, app_env::AppEnv, app_error::AppError, ws_messages::ScreenStatus};
 
+/// The DRM connector files read to get the screen status
+pub const DRM_CONNECTORS: [&str; 2] = [
+    "/sys/class/drm/card1-HDMI-A-1/enabled",
+    "/sys/class/drm/card1-HDMI-A-2/enabled",
+];
+
+/// The executable used to toggle the screen
+pub const BUSCTL: &str = "busctl";
+
 /// Using tokio::join_all causes this issue
 #[allow(clippy::unsafe_derive_deserialize)]
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,15 +29,16 @@ pub struct SysInfo {
 impl SysInfo {
     /// Check the screen status, maybe put this value in an .env, as it can change depending which por
     pub async fn screen_status() -> Option<ScreenStatus> {
-        let get = |num: u8| async move {
-            read_to_string(format!("/sys/class/drm/card1-HDMI-A-{num}/enabled"))
+        let get = |path: &'static str| async move {
+            read_to_string(path)
                 .await
                 .unwrap_or_default()
                 .trim()
                 .to_owned()
         };
 
-        let status = <[String; 2]>::from(tokio::join!(get(1), get(2)));
+        let status =
+            <[String; 2]>::from(tokio::join!(get(DRM_CONNECTORS[0]), get(DRM_CONNECTORS[1])));
         if status.contains(&"enabled".into()) {
             Some(ScreenStatus::On)
         } else if status.contains(&"disabled".into()) {
@@ -40,11 +48,22 @@ impl SysInfo {
         }
     }
 
-    /// Attempt to toggle the status of the screen
-    async fn toggle_screen(status: ScreenStatus) -> Result<Output, AppError> {
+    /// The DBus session address used by busctl.
+    /// Set by systemd for user services, else use the bus in the runtime directory, and finally attempt to guess the path
+    pub fn dbus_address() -> String {
+        if let Ok(address) = std::env::var("DBUS_SESSION_BUS_ADDRESS") {
+            return address;
+        }
+        if let Ok(runtime_dir) = std::env::var("XDG_RUNTIME_DIR") {
+            return format!("unix:path={runtime_dir}/bus");
+        }
         let uid = std::env::var("UID").unwrap_or_else(|_| "1000".to_string());
-        let dbus_address = format!("unix:path=/run/user/{uid}/bus");
-        tokio::process::Command::new("busctl")
+        format!("unix:path=/run/user/{uid}/bus")
+    }
+
+    /// Attempt to toggle the status of the screen, error if busctl exits unsuccessfully
+    pub async fn toggle_screen(status: &ScreenStatus) -> Result<(), AppError> {
+        let output = tokio::process::Command::new(BUSCTL)
             .args([
                 "--user",
                 "set-property",
@@ -55,18 +74,17 @@ impl SysInfo {
                 "i",
                 status.get_arg_value(),
             ])
-            .env("DBUS_SESSION_BUS_ADDRESS", dbus_address)
+            .env("DBUS_SESSION_BUS_ADDRESS", Self::dbus_address())
             .output()
             .await
-            .map_err(AppError::Io)
-    }
-
-    pub async fn turn_on() -> Result<Output, AppError> {
-        Self::toggle_screen(ScreenStatus::On).await
-    }
-
-    pub async fn turn_off() -> Result<Output, AppError> {
-        Self::toggle_screen(ScreenStatus::Off).await
+            .map_err(|e| AppError::ScreenCommand(format!("busctl: {e}")))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(AppError::ScreenCommand(
+                String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            ))
+        }
     }
 
     /// Get uptime by reading, and parsing, /proc/uptime file
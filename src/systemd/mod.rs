@@ -0,0 +1,588 @@
+mod unit;
+
+use crate::app_error::AppError;
+use std::{
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+use unit::Unit;
+pub use unit::{Harden, Restart, UnitFile, UnitFiles, UnitOptions};
+
+const SYSTEMCTL: &str = "systemctl";
+const LOGINCTL: &str = "loginctl";
+const APP_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Install the service system wide, or as a systemd user service of the current user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    System,
+    User,
+}
+
+impl Scope {
+    pub const fn from_user_flag(user: bool) -> Self {
+        if user { Self::User } else { Self::System }
+    }
+
+    /// Directory that the unit file is placed in, for user services this is ~/.config/systemd/user
+    fn unit_dir(self) -> Result<PathBuf, AppError> {
+        match self {
+            Self::System => Ok(PathBuf::from("/etc/systemd/system")),
+            Self::User => env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|i| PathBuf::from(i).join(".config")))
+                .map(|i| i.join("systemd").join("user"))
+                .ok_or(AppError::InvalidUser),
+        }
+    }
+}
+
+/// External commands, and the sudo check, used by install and uninstall, so that they can be replaced in tests
+trait CommandRunner {
+    /// Run a command, an unsuccessful exit status is an error
+    fn run(&self, program: &str, args: &[&str]) -> Result<(), AppError>;
+    fn running_as(&self) -> sudo::RunningAs;
+}
+
+/// Runs the actual commands
+struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<(), AppError> {
+        run(Command::new(program).args(args)).map(|_| ())
+    }
+
+    fn running_as(&self) -> sudo::RunningAs {
+        sudo::check()
+    }
+}
+
+/// Run a command, and check its exit status, if unsuccessful the error contains the command and its stderr, or stdout if stderr is empty
+fn run(command: &mut Command) -> Result<Output, AppError> {
+    let output = command.output()?;
+    if output.status.success() {
+        return Ok(output);
+    }
+    let program = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|i| i.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let message = [&output.stderr, &output.stdout]
+        .into_iter()
+        .map(|i| String::from_utf8_lossy(i).trim().to_owned())
+        .find(|i| !i.is_empty())
+        .unwrap_or_else(|| output.status.to_string());
+    Err(AppError::Systemd(program, message))
+}
+
+/// Get user name, to check if is sudo
+fn get_user_name() -> Option<String> {
+    std::env::var("SUDO_USER").map_or(None, |user_name| {
+        if user_name == "root" || user_name.is_empty() {
+            None
+        } else {
+            Some(user_name)
+        }
+    })
+}
+
+/// Install, and uninstall, units for a given scope, unit files are written relative to `root`, which is `/` unless testing
+struct Systemd<R: CommandRunner> {
+    scope: Scope,
+    root: PathBuf,
+    runner: R,
+}
+
+impl Systemd<SystemRunner> {
+    fn new(scope: Scope) -> Self {
+        Self {
+            scope,
+            root: PathBuf::from("/"),
+            runner: SystemRunner,
+        }
+    }
+}
+
+impl<R: CommandRunner> Systemd<R> {
+    /// System services need to be installed as sudo, user services as the user themselves
+    fn check_privileges(&self) -> Result<(), AppError> {
+        match (self.scope, self.runner.running_as()) {
+            (Scope::System, sudo::RunningAs::Root) | (Scope::User, sudo::RunningAs::User) => Ok(()),
+            (Scope::System, _) => Err(AppError::NotRoot),
+            (Scope::User, _) => Err(AppError::UserScopeAsRoot),
+        }
+    }
+
+    /// The unit directory of the scope, within the root
+    fn unit_dir(&self) -> Result<PathBuf, AppError> {
+        let unit_dir = self.scope.unit_dir()?;
+        Ok(self
+            .root
+            .join(unit_dir.strip_prefix("/").unwrap_or(&unit_dir)))
+    }
+
+    /// Get filename for a systemd unit file
+    fn unit_path(&self, unit: &Unit) -> Result<PathBuf, AppError> {
+        Ok(self.unit_dir()?.join(unit.name()))
+    }
+
+    /// Run a systemctl command, with the --user flag if required
+    fn systemctl(&self, args: &[&str]) -> Result<(), AppError> {
+        match self.scope {
+            Scope::System => self.runner.run(SYSTEMCTL, args),
+            Scope::User => self.runner.run(SYSTEMCTL, &[&["--user"], args].concat()),
+        }
+    }
+
+    /// Stop, disable, and remove, every installed unit, from either the daemon or timer install modes
+    fn uninstall_units(&self) -> Result<(), AppError> {
+        let mut removed = false;
+        for unit in Unit::ALL {
+            let path = self.unit_path(&unit)?;
+            if !path.exists() {
+                continue;
+            }
+            let name = unit.name();
+            tracing::info!("Stopping {name}");
+            self.systemctl(&["stop", &name])?;
+
+            if unit.enabled() {
+                tracing::info!("Disabling {name}");
+                self.systemctl(&["disable", &name])?;
+            }
+
+            tracing::info!("Removing {name} file");
+            fs::remove_file(path)?;
+            removed = true;
+        }
+        if removed {
+            tracing::info!("Reload daemon-service");
+            self.systemctl(&["daemon-reload"])?;
+        }
+        Ok(())
+    }
+
+    /// Write the unit files, and enable & start the service, or the timers.
+    /// For user services, lingering is enabled, so that the user's service manager is started at boot
+    fn install_units(&self, options: &UnitOptions) -> Result<(), AppError> {
+        let units = options.units();
+
+        tracing::info!("Create unit files");
+        fs::create_dir_all(self.unit_dir()?)?;
+        let mut paths = vec![];
+        for (unit, contents) in &units {
+            let path = self.unit_path(unit)?;
+            tracing::info!("Write {}", unit.name());
+            let mut file = fs::File::create(&path)?;
+            paths.push(path);
+            file.write_all(contents.as_bytes())?;
+        }
+
+        if self.scope == Scope::User {
+            tracing::info!("Enable lingering");
+            self.runner.run(LOGINCTL, &["enable-linger"])?;
+        }
+
+        let names = units
+            .iter()
+            .filter(|(unit, _)| unit.enabled())
+            .map(|(unit, _)| unit.name())
+            .collect::<Vec<_>>();
+        tracing::info!("Reload systemctl daemon, and enable {}", names.join(", "));
+        if let Err(e) = self.systemctl(&["daemon-reload"]).and_then(|()| {
+            names
+                .iter()
+                .try_for_each(|name| self.systemctl(&["enable", name]))
+        }) {
+            self.rollback(&paths);
+            return Err(e);
+        }
+
+        for name in &names {
+            tracing::info!("Start {name}");
+            self.systemctl(&["start", name])?;
+        }
+
+        for name in &names {
+            tracing::info!("Check {name} is active");
+            self.systemctl(&["is-active", name])?;
+        }
+        Ok(())
+    }
+
+    /// Disable, and remove the unit files, after a failed enable, so that a broken service isn't left installed
+    fn rollback(&self, paths: &[PathBuf]) {
+        tracing::info!("Rolling back, removing unit files");
+        for path in paths {
+            if let Some(name) = path.file_name().and_then(|i| i.to_str()) {
+                self.systemctl(&["disable", name]).ok();
+            }
+            if let Err(e) = fs::remove_file(path) {
+                tracing::error!("unable to remove unit file: {e}");
+            }
+        }
+        if let Err(e) = self.systemctl(&["daemon-reload"]) {
+            tracing::error!("{e}");
+        }
+    }
+
+    /// Install the service, or timers, removing any existing units first
+    fn install(&self, options: &UnitOptions) -> Result<(), AppError> {
+        self.check_privileges()?;
+        self.uninstall_units()?;
+        self.install_units(options)?;
+        tracing::info!("Installed service");
+        Ok(())
+    }
+
+    /// Uninstall the service, and any timers
+    fn uninstall(&self) -> Result<(), AppError> {
+        self.check_privileges()?;
+        self.uninstall_units()?;
+        tracing::info!("Uninstalled service");
+        Ok(())
+    }
+
+    /// The unit files that would be written by install
+    fn dry_run(&self, options: &UnitOptions) -> Result<UnitFiles, AppError> {
+        options
+            .units()
+            .into_iter()
+            .map(|(unit, contents)| {
+                Ok(UnitFile {
+                    path: self.unit_path(&unit)?,
+                    contents,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(UnitFiles)
+    }
+}
+
+/// Install the service, or timers, via systemd, removing any existing units first
+pub fn install(options: &UnitOptions) -> Result<(), AppError> {
+    Systemd::new(options.scope).install(options)
+}
+
+/// The unit files that would be written by install, without touching the file system or systemd
+pub fn dry_run(options: &UnitOptions) -> Result<UnitFiles, AppError> {
+    Systemd::new(options.scope).dry_run(options)
+}
+
+/// Uninstall the service, and any timers, from systemd
+pub fn uninstall(scope: Scope) -> Result<(), AppError> {
+    Systemd::new(scope).uninstall()
+}
+
+/// cargo watch -q -c -w src/ -x 'test systemd_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{
+        C, S,
+        systemd::unit::{Harden, Restart},
+    };
+    use jiff::civil::Time;
+
+    /// Records each command, and fails any command which starts with `fail`
+    struct MockRunner {
+        calls: RefCell<Vec<String>>,
+        fail: Option<&'static str>,
+        as_root: bool,
+    }
+
+    impl CommandRunner for MockRunner {
+        fn run(&self, program: &str, args: &[&str]) -> Result<(), AppError> {
+            let call = std::iter::once(program)
+                .chain(args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.calls.borrow_mut().push(C!(call));
+            match self.fail {
+                Some(fail) if call.starts_with(fail) => Err(AppError::Systemd(call, S!("failed"))),
+                _ => Ok(()),
+            }
+        }
+
+        fn running_as(&self) -> sudo::RunningAs {
+            if self.as_root {
+                sudo::RunningAs::Root
+            } else {
+                sudo::RunningAs::User
+            }
+        }
+    }
+
+    /// A Systemd with a temporary root, and a mock runner, the root is removed if it already exists
+    fn test_systemd(name: &str, scope: Scope, fail: Option<&'static str>) -> Systemd<MockRunner> {
+        let root = std::env::temp_dir().join(format!(
+            "screen_control_test_systemd_{name}_{}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&root).ok();
+        Systemd {
+            scope,
+            root,
+            runner: MockRunner {
+                calls: RefCell::new(vec![]),
+                fail,
+                as_root: scope == Scope::System,
+            },
+        }
+    }
+
+    fn test_options(scope: Scope) -> UnitOptions {
+        UnitOptions {
+            scope,
+            bin_path: PathBuf::from("/home/pi/screen_control.d/screen_control"),
+            working_dir: PathBuf::from("/home/pi/screen_control.d"),
+            env_file: None,
+            environment: vec![],
+            user: (scope == Scope::System).then(|| S!("pi")),
+            group: (scope == Scope::System).then(|| S!("pi")),
+            restart: Restart::Always,
+            restart_sec: 5,
+            watchdog_sec: 120,
+            runtime_dir: None,
+            no_harden: vec![Harden::All],
+            timers: None,
+        }
+    }
+
+    impl Systemd<MockRunner> {
+        /// Return, and clear, the recorded commands
+        fn calls(&self) -> Vec<String> {
+            self.runner.calls.take()
+        }
+
+        fn unit_exists(&self, name: &str) -> bool {
+            self.unit_dir().unwrap().join(name).exists()
+        }
+
+        fn remove_root(&self) {
+            fs::remove_dir_all(&self.root).ok();
+        }
+    }
+
+    const SERVICE: &str = "screen_control.service";
+
+    #[test]
+    fn systemd_run_exit_status() {
+        let result = run(Command::new("sh").args(["-c", "echo active"]));
+        assert_eq!(String::from_utf8_lossy(&result.unwrap().stdout), "active\n");
+
+        let result =
+            run(Command::new("sh").args(["-c", "echo failed; echo unit failed >&2; exit 3"]));
+        match result {
+            Err(AppError::Systemd(command, message)) => {
+                assert_eq!(command, "sh -c echo failed; echo unit failed >&2; exit 3");
+                assert_eq!(message, "unit failed");
+            }
+            _ => unreachable!("this indicates the test has failed"),
+        }
+
+        let result = run(Command::new("sh").args(["-c", "echo inactive; exit 3"]));
+        assert!(matches!(result, Err(AppError::Systemd(_, message)) if message == "inactive"));
+    }
+
+    #[test]
+    fn systemd_install_system() {
+        let systemd = test_systemd("install_system", Scope::System, None);
+        let options = test_options(Scope::System);
+        systemd.install(&options).unwrap();
+
+        let path = systemd.unit_dir().unwrap().join(SERVICE);
+        assert!(path.starts_with(systemd.root.join("etc/systemd/system")));
+        assert_eq!(fs::read_to_string(path).unwrap(), options.service_file());
+        assert_eq!(
+            systemd.calls(),
+            [
+                "systemctl daemon-reload",
+                "systemctl enable screen_control.service",
+                "systemctl start screen_control.service",
+                "systemctl is-active screen_control.service",
+            ]
+        );
+
+        // An existing install is removed first
+        systemd.install(&options).unwrap();
+        assert_eq!(
+            systemd.calls(),
+            [
+                "systemctl stop screen_control.service",
+                "systemctl disable screen_control.service",
+                "systemctl daemon-reload",
+                "systemctl daemon-reload",
+                "systemctl enable screen_control.service",
+                "systemctl start screen_control.service",
+                "systemctl is-active screen_control.service",
+            ]
+        );
+
+        systemd.uninstall().unwrap();
+        assert!(!systemd.unit_exists(SERVICE));
+        assert_eq!(
+            systemd.calls(),
+            [
+                "systemctl stop screen_control.service",
+                "systemctl disable screen_control.service",
+                "systemctl daemon-reload",
+            ]
+        );
+
+        // Nothing to uninstall
+        systemd.uninstall().unwrap();
+        assert!(systemd.calls().is_empty());
+        systemd.remove_root();
+    }
+
+    #[test]
+    fn systemd_install_user() {
+        let systemd = test_systemd("install_user", Scope::User, None);
+        systemd.install(&test_options(Scope::User)).unwrap();
+        assert!(systemd.unit_exists(SERVICE));
+        assert_eq!(
+            systemd.calls(),
+            [
+                "loginctl enable-linger",
+                "systemctl --user daemon-reload",
+                "systemctl --user enable screen_control.service",
+                "systemctl --user start screen_control.service",
+                "systemctl --user is-active screen_control.service",
+            ]
+        );
+
+        systemd.uninstall().unwrap();
+        assert_eq!(
+            systemd.calls(),
+            [
+                "systemctl --user stop screen_control.service",
+                "systemctl --user disable screen_control.service",
+                "systemctl --user daemon-reload",
+            ]
+        );
+        systemd.remove_root();
+    }
+
+    #[test]
+    fn systemd_install_timers() {
+        let systemd = test_systemd("install_timers", Scope::System, None);
+        // Replace an existing daemon install
+        systemd.install(&test_options(Scope::System)).unwrap();
+        systemd.calls();
+
+        let options = UnitOptions {
+            timers: Some((Time::constant(8, 0, 0, 0), Time::constant(22, 0, 0, 0))),
+            ..test_options(Scope::System)
+        };
+        systemd.install(&options).unwrap();
+        assert!(!systemd.unit_exists(SERVICE));
+        for name in [
+            "screen_control-on.service",
+            "screen_control-on.timer",
+            "screen_control-off.service",
+            "screen_control-off.timer",
+        ] {
+            assert!(systemd.unit_exists(name));
+        }
+        assert_eq!(
+            systemd.calls(),
+            [
+                "systemctl stop screen_control.service",
+                "systemctl disable screen_control.service",
+                "systemctl daemon-reload",
+                "systemctl daemon-reload",
+                "systemctl enable screen_control-on.timer",
+                "systemctl enable screen_control-off.timer",
+                "systemctl start screen_control-on.timer",
+                "systemctl start screen_control-off.timer",
+                "systemctl is-active screen_control-on.timer",
+                "systemctl is-active screen_control-off.timer",
+            ]
+        );
+
+        systemd.uninstall().unwrap();
+        assert!(
+            fs::read_dir(systemd.unit_dir().unwrap())
+                .unwrap()
+                .next()
+                .is_none()
+        );
+        assert_eq!(
+            systemd.calls(),
+            [
+                "systemctl stop screen_control-on.timer",
+                "systemctl disable screen_control-on.timer",
+                "systemctl stop screen_control-off.timer",
+                "systemctl disable screen_control-off.timer",
+                "systemctl stop screen_control-on.service",
+                "systemctl stop screen_control-off.service",
+                "systemctl daemon-reload",
+            ]
+        );
+        systemd.remove_root();
+    }
+
+    #[test]
+    fn systemd_install_enable_rollback() {
+        let systemd = test_systemd("enable_rollback", Scope::System, Some("systemctl enable"));
+        let result = systemd.install(&test_options(Scope::System));
+        assert!(matches!(result, Err(AppError::Systemd(..))));
+        assert!(!systemd.unit_exists(SERVICE));
+        assert_eq!(
+            systemd.calls(),
+            [
+                "systemctl daemon-reload",
+                "systemctl enable screen_control.service",
+                "systemctl disable screen_control.service",
+                "systemctl daemon-reload",
+            ]
+        );
+        systemd.remove_root();
+    }
+
+    #[test]
+    fn systemd_install_start_failed() {
+        let systemd = test_systemd("start_failed", Scope::System, Some("systemctl is-active"));
+        let result = systemd.install(&test_options(Scope::System));
+        assert!(matches!(result, Err(AppError::Systemd(..))));
+        // Enabled, so is left installed for investigation
+        assert!(systemd.unit_exists(SERVICE));
+        systemd.remove_root();
+    }
+
+    #[test]
+    fn systemd_install_privileges() {
+        let mut systemd = test_systemd("privileges", Scope::System, None);
+        systemd.runner.as_root = false;
+        let result = systemd.install(&test_options(Scope::System));
+        assert!(matches!(result, Err(AppError::NotRoot)));
+        assert!(matches!(systemd.uninstall(), Err(AppError::NotRoot)));
+
+        systemd.scope = Scope::User;
+        systemd.runner.as_root = true;
+        let result = systemd.install(&test_options(Scope::User));
+        assert!(matches!(result, Err(AppError::UserScopeAsRoot)));
+
+        assert!(systemd.calls().is_empty());
+        assert!(!systemd.root.exists());
+    }
+
+    #[test]
+    fn systemd_dry_run() {
+        let systemd = test_systemd("dry_run", Scope::System, None);
+        let options = test_options(Scope::System);
+        let result = systemd.dry_run(&options).unwrap();
+        assert_eq!(result.0.len(), 1);
+        assert_eq!(result.0[0].contents, options.service_file());
+        assert!(!systemd.root.exists());
+        assert!(systemd.calls().is_empty());
+    }
+}
@@ -0,0 +1,497 @@
+use std::{env, fmt, path::PathBuf};
+
+use clap::ValueEnum;
+use jiff::civil::Time;
+use serde::Serialize;
+
+use crate::{
+    C, S, app_env::AppEnv, app_error::AppError, cli::InstallArgs, ws_messages::ScreenStatus,
+};
+
+use super::{APP_NAME, Scope, get_user_name};
+
+/// The systemd Restart= policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Restart {
+    No,
+    Always,
+    OnSuccess,
+    OnFailure,
+    OnAbnormal,
+    OnAbort,
+    OnWatchdog,
+}
+
+impl fmt::Display for Restart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match self {
+            Self::No => "no",
+            Self::Always => "always",
+            Self::OnSuccess => "on-success",
+            Self::OnFailure => "on-failure",
+            Self::OnAbnormal => "on-abnormal",
+            Self::OnAbort => "on-abort",
+            Self::OnWatchdog => "on-watchdog",
+        };
+        write!(f, "{value}")
+    }
+}
+
+/// The units that can be installed, either the daemon, or, in timer mode, a oneshot service and its timer for each screen change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unit {
+    Daemon,
+    Oneshot(ScreenStatus),
+    Timer(ScreenStatus),
+}
+
+impl Unit {
+    /// Every unit, timers before the services they start, used on uninstall
+    pub const ALL: [Self; 5] = [
+        Self::Daemon,
+        Self::Timer(ScreenStatus::On),
+        Self::Timer(ScreenStatus::Off),
+        Self::Oneshot(ScreenStatus::On),
+        Self::Oneshot(ScreenStatus::Off),
+    ];
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::Daemon => format!("{APP_NAME}.service"),
+            Self::Oneshot(status) => format!("{APP_NAME}-{status}.service"),
+            Self::Timer(status) => format!("{APP_NAME}-{status}.timer"),
+        }
+    }
+
+    /// Units that are enabled, and started, on install, the oneshot services are only started by their timers
+    pub const fn enabled(&self) -> bool {
+        !matches!(self, Self::Oneshot(_))
+    }
+}
+
+/// Sandboxing options added to the unit by default, each can be disabled with `--no-harden`, e.g. if a backend needs extra access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Harden {
+    /// Disable all hardening
+    All,
+    NoNewPrivileges,
+    ProtectSystem,
+    ProtectHome,
+    PrivateTmp,
+    RestrictAddressFamilies,
+    /// StateDirectory, and write access to the runtime directory, for the control socket
+    ReadWritePaths,
+    SystemCallFilter,
+}
+
+/// Get the uid of a user from /etc/passwd
+fn get_uid(user: &str) -> Option<u32> {
+    std::fs::read_to_string("/etc/passwd")
+        .ok()?
+        .lines()
+        .map(|i| i.split(':').collect::<Vec<_>>())
+        .find(|i| i.first() == Some(&user))
+        .and_then(|i| i.get(2).and_then(|uid| uid.parse().ok()))
+}
+
+/// Escape a value for use in a unit file, quoted if it contains whitespace, quotes, or backslashes
+fn escape(value: &str) -> String {
+    let escaped = value.replace('%', "%%");
+    if escaped.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        escaped
+    }
+}
+
+/// Fully resolved options used to generate the service unit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitOptions {
+    pub scope: Scope,
+    pub bin_path: PathBuf,
+    pub working_dir: PathBuf,
+    pub env_file: Option<PathBuf>,
+    pub environment: Vec<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub restart: Restart,
+    pub restart_sec: u32,
+    pub watchdog_sec: u32,
+    pub runtime_dir: Option<String>,
+    pub no_harden: Vec<Harden>,
+    /// The on and off times, if installing timers instead of the daemon
+    pub timers: Option<(Time, Time)>,
+}
+
+impl UnitOptions {
+    /// Resolve the install arguments, defaulting to the path of this executable, its directory as the working directory,
+    /// and for system services the sudo user
+    pub fn new(args: &InstallArgs) -> Result<Self, AppError> {
+        let scope = Scope::from_user_flag(args.user);
+        let bin_path = match &args.bin_path {
+            Some(path) => path.clone(),
+            None => env::current_exe()?,
+        };
+        let working_dir = match &args.working_dir {
+            Some(path) => path.clone(),
+            None => bin_path
+                .parent()
+                .map_or_else(env::current_dir, |i| Ok(i.to_path_buf()))?,
+        };
+        let (user, group) = match scope {
+            Scope::System => {
+                let user = args
+                    .service_user
+                    .clone()
+                    .or_else(get_user_name)
+                    .or_else(|| args.dry_run.then(|| env::var("USER").ok()).flatten())
+                    .ok_or(AppError::InvalidUser)?;
+                let group = args.service_group.clone().unwrap_or_else(|| user.clone());
+                (Some(user), Some(group))
+            }
+            Scope::User => (None, None),
+        };
+        // System services have no XDG_RUNTIME_DIR, so use the service user's, %t is the user's runtime directory for user services.
+        // If the user's can't be resolved the service is given its own, as the temp directory is private to the unit
+        let runtime_dir = match scope {
+            Scope::System => user
+                .as_deref()
+                .and_then(get_uid)
+                .map(|uid| format!("/run/user/{uid}")),
+            Scope::User => Some(S!("%t")),
+        };
+        let timers = if args.timers {
+            Some(AppEnv::get_schedule(
+                args.env_file.as_deref(),
+                &args.env,
+                &working_dir,
+            )?)
+        } else {
+            None
+        };
+        Ok(Self {
+            scope,
+            bin_path,
+            working_dir,
+            env_file: args.env_file.clone(),
+            environment: args.env.clone(),
+            user,
+            group,
+            restart: args.restart,
+            restart_sec: args.restart_sec,
+            watchdog_sec: args.watchdog_sec,
+            runtime_dir,
+            no_harden: args.no_harden.clone(),
+            timers,
+        })
+    }
+
+    fn hardened(&self, harden: Harden) -> bool {
+        !self.no_harden.contains(&Harden::All) && !self.no_harden.contains(&harden)
+    }
+
+    /// The sandboxing options, the file system is read-only apart from the state directory, and the runtime directory,
+    /// which contains the control socket and the session bus
+    fn hardening(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if self.hardened(Harden::NoNewPrivileges) {
+            lines.push(S!("NoNewPrivileges=true"));
+        }
+        if self.hardened(Harden::ProtectSystem) {
+            lines.push(S!("ProtectSystem=strict"));
+        }
+        if self.hardened(Harden::ProtectHome) {
+            lines.push(S!("ProtectHome=read-only"));
+        }
+        if self.hardened(Harden::PrivateTmp) {
+            lines.push(S!("PrivateTmp=true"));
+        }
+        if self.hardened(Harden::RestrictAddressFamilies) {
+            // AF_NETLINK is used to get the local IP address
+            lines.push(S!(
+                "RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK"
+            ));
+        }
+        if self.hardened(Harden::ReadWritePaths) {
+            lines.push(format!("StateDirectory={APP_NAME}"));
+            if let Some(runtime_dir) = &self.runtime_dir {
+                lines.push(format!("ReadWritePaths=-{runtime_dir}"));
+            }
+        }
+        if self.hardened(Harden::SystemCallFilter) {
+            lines.push(S!("SystemCallFilter=@system-service"));
+            lines.push(S!("SystemCallErrorNumber=EPERM"));
+        }
+        lines
+    }
+
+    /// The environment, command, and user, shared by the daemon and the oneshot timer services.
+    /// The oneshot services run the on/off cli command, with no command the daemon is run
+    fn exec_lines(&self, command: Option<&ScreenStatus>) -> Vec<String> {
+        let mut lines = vec![];
+        if self.scope == Scope::System
+            && let Some(runtime_dir) = &self.runtime_dir
+        {
+            lines.push(format!("Environment=XDG_RUNTIME_DIR={runtime_dir}"));
+        }
+        lines.extend(
+            self.environment
+                .iter()
+                .map(|i| format!("Environment={}", escape(i))),
+        );
+        if let Some(env_file) = &self.env_file {
+            lines.push(format!(
+                "EnvironmentFile={}",
+                escape(&env_file.display().to_string())
+            ));
+        }
+        let bin_path = escape(&self.bin_path.display().to_string());
+        lines.extend([
+            command.map_or_else(
+                || format!("ExecStart={bin_path}"),
+                |command| format!("ExecStart={bin_path} {command}"),
+            ),
+            format!(
+                "WorkingDirectory={}",
+                escape(&self.working_dir.display().to_string())
+            ),
+            format!("SyslogIdentifier={APP_NAME}"),
+        ]);
+        if let Some(user) = &self.user {
+            lines.push(format!("User={user}"));
+        }
+        if let Some(group) = &self.group {
+            lines.push(format!("Group={group}"));
+        }
+        lines
+    }
+
+    /// Create a systemd service file, with correct details.
+    /// User services are started with the graphical session, so have access to the session bus.
+    /// The daemon notifies systemd once ready, and pings the watchdog from the message handler
+    pub fn service_file(&self) -> String {
+        let mut lines = vec![S!("[Unit]"), format!("Description={APP_NAME}")];
+        lines.extend(
+            match self.scope {
+                Scope::System => ["After=network-online.target", "Wants=network-online.target"],
+                Scope::User => [
+                    "After=graphical-session.target",
+                    "PartOf=graphical-session.target",
+                ],
+            }
+            .map(String::from),
+        );
+        lines.extend([
+            S!("StartLimitIntervalSec=0"),
+            S!(),
+            S!("[Service]"),
+            S!("Type=notify"),
+        ]);
+        lines.extend(self.exec_lines(None));
+        if self.runtime_dir.is_none() {
+            lines.push(format!("RuntimeDirectory={APP_NAME}"));
+        }
+        lines.extend([
+            format!("Restart={}", self.restart),
+            format!("RestartSec={}", self.restart_sec),
+        ]);
+        if self.watchdog_sec > 0 {
+            lines.push(format!("WatchdogSec={}", self.watchdog_sec));
+        }
+        lines.extend(self.hardening());
+        lines.extend([S!(), S!("[Install]")]);
+        lines.push(match self.scope {
+            Scope::System => S!("WantedBy=multi-user.target"),
+            Scope::User => S!("WantedBy=graphical-session.target"),
+        });
+        lines.push(S!());
+        lines.join("\n")
+    }
+
+    /// A oneshot service to turn the screen on or off, started by its timer
+    fn oneshot_file(&self, status: &ScreenStatus) -> String {
+        let mut lines = vec![
+            S!("[Unit]"),
+            format!("Description={APP_NAME} screen {status}"),
+            S!(),
+            S!("[Service]"),
+            S!("Type=oneshot"),
+        ];
+        lines.extend(self.exec_lines(Some(status)));
+        lines.extend(self.hardening());
+        lines.push(S!());
+        lines.join("\n")
+    }
+
+    /// A timer to start the oneshot service each day at the given time, Persistent so a missed change is run at boot
+    fn timer_file(status: &ScreenStatus, time: Time) -> String {
+        let time = time.strftime("%H:%M");
+        [
+            S!("[Unit]"),
+            format!("Description={APP_NAME} screen {status} at {time}"),
+            S!(),
+            S!("[Timer]"),
+            format!("OnCalendar=*-*-* {time}:00"),
+            S!("Persistent=true"),
+            S!(),
+            S!("[Install]"),
+            S!("WantedBy=timers.target"),
+            S!(),
+        ]
+        .join("\n")
+    }
+
+    /// Every unit to install, and its contents, either the daemon, or a oneshot service and timer for both on and off
+    pub fn units(&self) -> Vec<(Unit, String)> {
+        match self.timers {
+            Some((time_on, time_off)) => {
+                [(ScreenStatus::On, time_on), (ScreenStatus::Off, time_off)]
+                    .into_iter()
+                    .flat_map(|(status, time)| {
+                        [
+                            (Unit::Oneshot(C!(status)), self.oneshot_file(&status)),
+                            (Unit::Timer(C!(status)), Self::timer_file(&status, time)),
+                        ]
+                    })
+                    .collect()
+            }
+            None => vec![(Unit::Daemon, self.service_file())],
+        }
+    }
+}
+
+/// A unit file, and its location, as displayed by `install --dry-run`
+#[derive(Debug, Serialize)]
+pub struct UnitFile {
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+impl fmt::Display for UnitFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "# {}\n{}", self.path.display(), self.contents)
+    }
+}
+
+/// Every unit file that would be written by install
+#[derive(Debug, Serialize)]
+pub struct UnitFiles(pub Vec<UnitFile>);
+
+impl fmt::Display for UnitFiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, unit_file) in self.0.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{unit_file}")?;
+        }
+        Ok(())
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test systemd_unit -- --nocapture'
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_options(scope: Scope) -> UnitOptions {
+        UnitOptions {
+            scope,
+            bin_path: PathBuf::from("/home/pi/screen_control.d/screen_control"),
+            working_dir: PathBuf::from("/home/pi/screen_control.d"),
+            env_file: None,
+            environment: vec![],
+            user: (scope == Scope::System).then(|| S!("pi")),
+            group: (scope == Scope::System).then(|| S!("pi")),
+            restart: Restart::Always,
+            restart_sec: 5,
+            watchdog_sec: 120,
+            runtime_dir: Some(S!(match scope {
+                Scope::System => "/run/user/1000",
+                Scope::User => "%t",
+            })),
+            no_harden: vec![],
+            timers: None,
+        }
+    }
+
+    #[test]
+    fn systemd_unit_escape() {
+        assert_eq!(escape("/usr/bin/screen_control"), "/usr/bin/screen_control");
+        assert_eq!(escape("/home/pi/my dir"), "\"/home/pi/my dir\"");
+        assert_eq!(escape("KEY=\"value\""), "\"KEY=\\\"value\\\"\"");
+        assert_eq!(escape("KEY=50%"), "KEY=50%%");
+    }
+
+    #[test]
+    fn systemd_unit_system_default() {
+        insta::assert_snapshot!(test_options(Scope::System).service_file());
+    }
+
+    #[test]
+    fn systemd_unit_user_default() {
+        insta::assert_snapshot!(test_options(Scope::User).service_file());
+    }
+
+    #[test]
+    fn systemd_unit_system_configured() {
+        let options = UnitOptions {
+            bin_path: PathBuf::from("/opt/screen control/screen_control"),
+            working_dir: PathBuf::from("/var/lib/screen_control"),
+            env_file: Some(PathBuf::from("/etc/screen_control/.env")),
+            environment: vec![S!("WAYLAND_DISPLAY=wayland-1"), S!("LOG_DEBUG=true")],
+            group: Some(S!("video")),
+            restart: Restart::OnFailure,
+            restart_sec: 30,
+            watchdog_sec: 0,
+            no_harden: vec![Harden::ProtectHome, Harden::SystemCallFilter],
+            ..test_options(Scope::System)
+        };
+        insta::assert_snapshot!(options.service_file());
+    }
+
+    #[test]
+    fn systemd_unit_timers() {
+        let options = UnitOptions {
+            timers: Some((Time::constant(8, 0, 0, 0), Time::constant(22, 30, 0, 0))),
+            ..test_options(Scope::System)
+        };
+        let units = options.units();
+        assert_eq!(
+            units
+                .iter()
+                .map(|(unit, _)| unit.name())
+                .collect::<Vec<_>>(),
+            [
+                "screen_control-on.service",
+                "screen_control-on.timer",
+                "screen_control-off.service",
+                "screen_control-off.timer"
+            ]
+        );
+        for (unit, contents) in units {
+            insta::assert_snapshot!(format!("systemd_unit_timers_{}", unit.name()), contents);
+        }
+    }
+
+    #[test]
+    fn systemd_unit_system_no_runtime_dir() {
+        let options = UnitOptions {
+            runtime_dir: None,
+            ..test_options(Scope::System)
+        };
+        let service_file = options.service_file();
+        assert!(service_file.contains("RuntimeDirectory=screen_control"));
+        assert!(!service_file.contains("XDG_RUNTIME_DIR"));
+        insta::assert_snapshot!(service_file);
+    }
+
+    #[test]
+    fn systemd_unit_no_harden() {
+        let options = UnitOptions {
+            no_harden: vec![Harden::All],
+            ..test_options(Scope::User)
+        };
+        insta::assert_snapshot!(options.service_file());
+    }
+}
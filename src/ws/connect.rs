@@ -1,18 +1,49 @@
-use crate::{app_env::AppEnv, app_error::AppError, message_handler::WsStream};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    C,
+    app_env::{AppEnv, Endpoint, TimeoutConfig, WsAuth, WsConfig},
+    app_error::AppError,
+    message_handler::WsStream,
+    ws::ProxyConfig,
+};
+use reqwest::Url;
+use rustls::{ClientConfig, pki_types::ServerName};
 use serde::{Deserialize, Serialize};
-use tokio_tungstenite::{self, connect_async, tungstenite::http::StatusCode};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::{
+    self, MaybeTlsStream, client_async_with_config,
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        handshake::client::Request,
+        http::{
+            HeaderValue, StatusCode,
+            header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL},
+        },
+    },
+};
+
+/// Maximum number of characters of an error response body to include in an error
+const SNIPPET_LEN: usize = 200;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct PostRequest<'a> {
     key: &'a str,
-    password: &'a str,
+    /// Not required when authenticating with a client certificate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<&'a str>,
 }
 
-impl<'a> From<&'a AppEnv> for PostRequest<'a> {
-    fn from(app_envs: &'a AppEnv) -> Self {
+impl<'a> From<&'a WsConfig> for PostRequest<'a> {
+    fn from(ws: &'a WsConfig) -> Self {
         Self {
-            key: &app_envs.ws_apikey,
-            password: &app_envs.ws_password,
+            key: &ws.apikey,
+            password: ws.password.as_deref(),
         }
     }
 }
@@ -23,39 +54,722 @@ struct PostResponse {
     response: String,
 }
 
-/// Make a https request to get an access token
-async fn get_auth_token(app_envs: &AppEnv) -> Result<String, AppError> {
-    Ok(reqwest::Client::builder()
-        .connect_timeout(std::time::Duration::from_millis(5000))
-        .gzip(true)
-        .brotli(true)
-        .user_agent(format!(
-            "{}/{}",
-            env!("CARGO_PKG_NAME"),
-            env!("CARGO_PKG_VERSION")
-        ))
-        .build()?
-        .post(&app_envs.ws_token_address)
-        .json(&PostRequest::from(app_envs))
-        .send()
-        .await?
-        .json::<PostResponse>()
-        .await?
-        .response)
-}
-
-/// Connect to wesbsocket server
-pub async fn ws_upgrade(app_envs: &AppEnv) -> Result<WsStream, AppError> {
-    let url = format!(
-        "{}/{}",
-        app_envs.ws_address,
-        get_auth_token(app_envs).await?
-    );
-    let (socket, response) = connect_async(url)
+/// An access token, the token server it was issued by, and when it should no longer be used
+#[derive(Debug)]
+struct CachedToken {
+    token: String,
+    token_address: String,
+    expires: Instant,
+}
+
+/// The start of a response body, on a single line, so that a html error page doesn't flood the logs
+fn snippet(body: &str) -> String {
+    let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    if body.chars().count() > SNIPPET_LEN {
+        format!("{}…", body.chars().take(SNIPPET_LEN).collect::<String>())
+    } else {
+        body
+    }
+}
+
+/// Opens the WS connection, the http client is re-used, and the access token is cached, so that a WS outage doesn't
+/// result in the api key and password being posted on every reconnect attempt. Both the token request, and the WS
+/// connection, go via the proxy, unless the host is in NO_PROXY, and share the same TLS config. Each step has its own
+/// timeout, so a half-open connection can't hang the connection sequence
+#[derive(Debug)]
+pub struct Connector {
+    client: reqwest::Client,
+    proxy: ProxyConfig,
+    timeouts: TimeoutConfig,
+    tls: Arc<ClientConfig>,
+    token: Option<CachedToken>,
+    token_ttl: Duration,
+    ws: WsConfig,
+}
+
+/// A duration in milliseconds, for an error message, or the status
+pub(super) fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+impl Connector {
+    pub fn new(app_envs: &AppEnv, ws: &WsConfig) -> Result<Self, AppError> {
+        // The system proxy envs are ignored by reqwest, as they've already been parsed into app_envs.proxy
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(app_envs.timeouts.connect)
+            .gzip(true)
+            .brotli(true)
+            .no_proxy()
+            .user_agent(format!(
+                "{}/{}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            ));
+        if app_envs.proxy.proxy.is_some() {
+            // Each endpoint's token server may, or may not, be in NO_PROXY
+            let proxy = C!(app_envs.proxy);
+            builder = builder.proxy(reqwest::Proxy::custom(move |url| {
+                url.host_str()
+                    .and_then(|host| proxy.for_host(host))
+                    .map(|i| C!(i.url))
+            }));
+        }
+        let tls = app_envs.tls.client_config()?;
+        Ok(Self {
+            client: builder
+                .use_preconfigured_tls(ClientConfig::clone(&tls))
+                .build()?,
+            proxy: C!(app_envs.proxy),
+            timeouts: app_envs.timeouts,
+            tls,
+            token: None,
+            token_ttl: app_envs.ws_token_ttl,
+            ws: C!(ws),
+        })
+    }
+
+    /// Make a https request to get an access token, 401 & 403 responses are treated separately, as retrying won't help
+    async fn request_token(&self, endpoint: &Endpoint) -> Result<String, AppError> {
+        let (status, body) = timeout(self.timeouts.token, async {
+            let response = self
+                .client
+                .post(&endpoint.token_address)
+                .json(&PostRequest::from(&self.ws))
+                .send()
+                .await?;
+            Ok::<_, AppError>((response.status(), response.text().await?))
+        })
         .await
-        .map_err(|i| AppError::TungsteniteConnect(i.to_string()))?;
-    match response.status() {
-        StatusCode::SWITCHING_PROTOCOLS => Ok(socket),
-        _ => Err(AppError::WsStatus),
+        .map_err(|_| AppError::TokenTimeout(millis(self.timeouts.token)))??;
+        match status.as_u16() {
+            200..=299 => serde_json::from_str::<PostResponse>(&body)
+                .map(|i| i.response)
+                .map_err(|_| AppError::TokenResponse(snippet(&body))),
+            code @ (401 | 403) => Err(AppError::TokenUnauthorized(code, snippet(&body))),
+            code @ 500..=599 => Err(AppError::TokenServer(code, snippet(&body))),
+            code => Err(AppError::TokenStatus(code, snippet(&body))),
+        }
+    }
+
+    /// Get an access token, from the cache if it hasn't yet expired, and was issued by this endpoint's token server
+    pub async fn get_token(&mut self, endpoint: &Endpoint) -> Result<String, AppError> {
+        if let Some(cached) = self
+            .token
+            .as_ref()
+            .filter(|i| i.expires > Instant::now() && i.token_address == endpoint.token_address)
+        {
+            return Ok(C!(cached.token));
+        }
+        let token = self.request_token(endpoint).await?;
+        self.token = Some(CachedToken {
+            token: C!(token),
+            token_address: C!(endpoint.token_address),
+            expires: Instant::now() + self.token_ttl,
+        });
+        Ok(token)
+    }
+
+    /// Open a TCP connection, via the proxy if required, and then complete the TLS handshake for a wss:// address
+    async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        tls: bool,
+    ) -> Result<MaybeTlsStream<TcpStream>, AppError> {
+        let stream = self.proxy.connect(host, port).await?;
+        if !tls {
+            return Ok(MaybeTlsStream::Plain(stream));
+        }
+        let server_name = ServerName::try_from(
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned(),
+        )
+        .map_err(|e| AppError::TungsteniteConnect(format!("invalid host '{host}': {e}")))?;
+        TlsConnector::from(Arc::clone(&self.tls))
+            .connect(server_name, stream)
+            .await
+            .map(MaybeTlsStream::Rustls)
+            .map_err(|e| AppError::TungsteniteConnect(format!("TLS handshake: {e}")))
+    }
+
+    /// Open a TCP connection, and complete any TLS handshake, to the endpoint's WS server, within the connect timeout
+    async fn connect_endpoint(
+        &self,
+        endpoint: &Endpoint,
+    ) -> Result<MaybeTlsStream<TcpStream>, AppError> {
+        let parsed = Url::parse(&endpoint.ws_address)
+            .map_err(|e| AppError::TungsteniteConnect(e.to_string()))?;
+        let (Some(host), Some(port)) = (parsed.host_str(), parsed.port_or_known_default()) else {
+            return Err(AppError::TungsteniteConnect(format!(
+                "invalid WS_ADDRESS: '{}'",
+                endpoint.ws_address
+            )));
+        };
+        timeout(
+            self.timeouts.connect,
+            self.connect(host, port, parsed.scheme() == "wss"),
+        )
+        .await
+        .map_err(|_| AppError::ConnectTimeout(millis(self.timeouts.connect)))?
+    }
+
+    /// Check that the endpoint's WS server is reachable, without requesting a token, or upgrading the connection
+    pub async fn probe(&self, endpoint: &Endpoint) -> Result<(), AppError> {
+        self.connect_endpoint(endpoint).await.map(|_| ())
+    }
+
+    /// The WS upgrade request, with the token either in the path, or in a header
+    fn upgrade_request(&self, endpoint: &Endpoint, token: &str) -> Result<Request, AppError> {
+        let invalid = |e: &dyn std::fmt::Display| AppError::TungsteniteConnect(e.to_string());
+        let (url, header) = match self.ws.auth {
+            WsAuth::Path => (format!("{}/{token}", endpoint.ws_address), None),
+            WsAuth::Bearer => (
+                C!(endpoint.ws_address),
+                Some((AUTHORIZATION, format!("Bearer {token}"))),
+            ),
+            WsAuth::Protocol => (
+                C!(endpoint.ws_address),
+                Some((SEC_WEBSOCKET_PROTOCOL, token.to_owned())),
+            ),
+        };
+        let mut request = url.into_client_request().map_err(|e| invalid(&e))?;
+        if let Some((name, value)) = header {
+            request.headers_mut().insert(
+                name,
+                HeaderValue::from_str(&value).map_err(|e| invalid(&e))?,
+            );
+        }
+        Ok(request)
+    }
+
+    /// Connect to wesbsocket server, the cached token is discarded once it has been used, or if the server rejects it
+    pub async fn ws_upgrade(&mut self, endpoint: &Endpoint) -> Result<WsStream, AppError> {
+        let token = self.get_token(endpoint).await?;
+        let request = self.upgrade_request(endpoint, &token)?;
+        let stream = self.connect_endpoint(endpoint).await?;
+        let (socket, response) = timeout(
+            self.timeouts.upgrade,
+            client_async_with_config(request, stream, None),
+        )
+        .await
+        .map_err(|_| AppError::UpgradeTimeout(millis(self.timeouts.upgrade)))?
+        .map_err(|i| {
+            if matches!(i, tungstenite::Error::Http(_)) {
+                self.token = None;
+            }
+            AppError::TungsteniteConnect(i.to_string())
+        })?;
+        self.token = None;
+        match response.status() {
+            StatusCode::SWITCHING_PROTOCOLS => Ok(socket),
+            _ => Err(AppError::WsStatus),
+        }
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test connect_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+pub mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        S,
+        tests::test_setup,
+        ws::{
+            Proxy,
+            proxy::tests::{Requests, http_proxy, socks_proxy},
+            tls::tests::TestPki,
+        },
+    };
+
+    /// Read a whole http request, the headers, and then content-length bytes of body
+    async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+        let mut buf = vec![];
+        let mut chunk = [0; 1024];
+        loop {
+            let len = stream.read(&mut chunk).await.unwrap_or_default();
+            if len == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..len]);
+            let request = String::from_utf8_lossy(&buf);
+            if let Some((head, body)) = request.split_once("\r\n\r\n") {
+                let content_length = head
+                    .lines()
+                    .find_map(|i| {
+                        i.to_lowercase()
+                            .strip_prefix("content-length:")
+                            .map(|i| i.trim().parse::<usize>().unwrap_or_default())
+                    })
+                    .unwrap_or_default();
+                if body.len() >= content_length {
+                    break;
+                }
+            }
+        }
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+
+    /// A stand-in token server, every request gets the same response, returns the address, and a count of requests received
+    pub async fn token_server(status: u16, body: String) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}/token", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&count);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let request = read_request(&mut stream).await;
+                assert!(request.starts_with("POST /token"));
+                assert!(request.contains(r#""key":"ws_apikey""#));
+                counter.fetch_add(1, Ordering::SeqCst);
+                let response = format!(
+                    "HTTP/1.1 {status} Stand-in\r\ncontent-type: text/plain\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.ok();
+                stream.shutdown().await.ok();
+            }
+        });
+        (address, count)
+    }
+
+    fn new_connector(app_envs: &AppEnv) -> Connector {
+        Connector::new(app_envs, app_envs.ws.as_ref().unwrap()).unwrap()
+    }
+
+    fn endpoint(ws_address: &str, token_address: &str) -> Endpoint {
+        Endpoint {
+            ws_address: ws_address.to_owned(),
+            token_address: token_address.to_owned(),
+        }
+    }
+
+    async fn test_connector(status: u16, body: &str) -> (Connector, Endpoint, Arc<AtomicUsize>) {
+        let (address, count) = token_server(status, body.to_owned()).await;
+        (
+            new_connector(&test_setup()),
+            endpoint("ws_address", &address),
+            count,
+        )
+    }
+
+    #[test]
+    fn connect_snippet() {
+        assert_eq!(snippet("  bad\n  gateway \n"), "bad gateway");
+        let result = snippet(&"a".repeat(500));
+        assert_eq!(result.chars().count(), SNIPPET_LEN + 1);
+        assert!(result.ends_with('…'));
+    }
+
+    #[tokio::test]
+    async fn connect_token_cached() {
+        let (mut connector, endpoint, count) = test_connector(200, r#"{"response":"abc"}"#).await;
+        assert_eq!(connector.get_token(&endpoint).await.unwrap(), "abc");
+        assert_eq!(connector.get_token(&endpoint).await.unwrap(), "abc");
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // A token from one endpoint isn't used for another
+        let (standby_address, standby_count) = token_server(200, S!(r#"{"response":"def"}"#)).await;
+        let standby = self::endpoint("ws_address", &standby_address);
+        assert_eq!(connector.get_token(&standby).await.unwrap(), "def");
+        assert_eq!(connector.get_token(&standby).await.unwrap(), "def");
+        assert_eq!(standby_count.load(Ordering::SeqCst), 1);
+        assert_eq!(connector.get_token(&endpoint).await.unwrap(), "abc");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // Expired, so requested again
+        connector.token_ttl = Duration::ZERO;
+        connector.token = None;
+        connector.get_token(&endpoint).await.unwrap();
+        connector.get_token(&endpoint).await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn connect_token_unauthorized() {
+        for status in [401, 403] {
+            let (mut connector, endpoint, _) = test_connector(status, "invalid key").await;
+            let result = connector.get_token(&endpoint).await;
+            assert!(
+                matches!(result, Err(AppError::TokenUnauthorized(code, ref body)) if code == status && body == "invalid key")
+            );
+            assert!(connector.token.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_token_server_error() {
+        let body = format!("<html>{}</html>", "x".repeat(500));
+        let (mut connector, endpoint, _) = test_connector(503, &body).await;
+        let result = connector.get_token(&endpoint).await;
+        assert!(
+            matches!(result, Err(AppError::TokenServer(503, ref body)) if body.starts_with("<html>xxx") && body.chars().count() == SNIPPET_LEN + 1)
+        );
+
+        let (mut connector, endpoint, _) = test_connector(429, "slow down").await;
+        let result = connector.get_token(&endpoint).await;
+        assert!(matches!(result, Err(AppError::TokenStatus(429, ref body)) if body == "slow down"));
+    }
+
+    #[tokio::test]
+    async fn connect_token_invalid_json() {
+        let (mut connector, endpoint, _) = test_connector(200, "<html>maintenance</html>").await;
+        let result = connector.get_token(&endpoint).await;
+        assert!(
+            matches!(result, Err(AppError::TokenResponse(ref body)) if body == "<html>maintenance</html>")
+        );
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("<html>maintenance</html>")
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_ws_outage_reuses_token() {
+        let (mut connector, mut endpoint, count) =
+            test_connector(200, r#"{"response":"abc"}"#).await;
+        // Nothing listening on the ws address
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        endpoint.ws_address = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        for _ in 0..3 {
+            assert!(matches!(
+                connector.ws_upgrade(&endpoint).await,
+                Err(AppError::Io(ref e)) if e.kind() == std::io::ErrorKind::ConnectionRefused
+            ));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(connector.token.is_some());
+    }
+
+    /// A stand-in WS server, accepts every upgrade request, and then closes once the client has closed
+    pub async fn ws_server() -> (String, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if let Ok(mut socket) = tokio_tungstenite::accept_async(stream).await {
+                        futures_util::StreamExt::next(&mut socket).await;
+                    }
+                });
+            }
+        });
+        (format!("ws://127.0.0.1:{port}"), port)
+    }
+
+    /// A stand-in WS server, for a single upgrade request, which returns the path, and the auth headers, of the request.
+    /// Any protocol is echoed back, as the client requires
+    async fn auth_ws_server() -> (String, tokio::sync::oneshot::Receiver<[Option<String>; 3]>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("ws://{}", listener.local_addr().unwrap());
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            #[expect(clippy::result_large_err)]
+            let callback =
+                |request: &Request, mut response: tungstenite::handshake::server::Response| {
+                    let header = |name| {
+                        request
+                            .headers()
+                            .get(name)
+                            .and_then(|i: &HeaderValue| i.to_str().ok())
+                            .map(ToOwned::to_owned)
+                    };
+                    let protocol = header(SEC_WEBSOCKET_PROTOCOL);
+                    if let Some(protocol) = &protocol {
+                        response.headers_mut().insert(
+                            SEC_WEBSOCKET_PROTOCOL,
+                            HeaderValue::from_str(protocol).unwrap(),
+                        );
+                    }
+                    tx.send([
+                        Some(request.uri().path().to_owned()),
+                        header(AUTHORIZATION),
+                        protocol,
+                    ])
+                    .ok();
+                    Ok(response)
+                };
+            let mut socket = tokio_tungstenite::accept_hdr_async(stream, callback)
+                .await
+                .unwrap();
+            futures_util::StreamExt::next(&mut socket).await;
+        });
+        (address, rx)
+    }
+
+    #[tokio::test]
+    async fn connect_auth() {
+        for (auth, expected) in [
+            (WsAuth::Path, [Some(S!("/abc")), None, None]),
+            (
+                WsAuth::Bearer,
+                [Some(S!("/")), Some(S!("Bearer abc")), None],
+            ),
+            (WsAuth::Protocol, [Some(S!("/")), None, Some(S!("abc"))]),
+        ] {
+            let (token_address, _) = token_server(200, S!(r#"{"response":"abc"}"#)).await;
+            let (ws_address, received) = auth_ws_server().await;
+            let mut app_envs = test_setup();
+            app_envs.ws.as_mut().unwrap().auth = auth;
+            let mut connector = new_connector(&app_envs);
+
+            let mut socket = connector
+                .ws_upgrade(&endpoint(&ws_address, &token_address))
+                .await
+                .unwrap();
+            socket.close(None).await.ok();
+            assert_eq!(received.await.unwrap(), expected);
+        }
+    }
+
+    async fn proxy_connector(
+        token_address: String,
+        proxy: &str,
+        no_proxy: &[&str],
+    ) -> (Connector, Endpoint, u16) {
+        let (ws_address, ws_port) = ws_server().await;
+        let mut app_envs = test_setup();
+        app_envs.proxy = ProxyConfig {
+            proxy: Some(Proxy::parse(proxy).unwrap()),
+            no_proxy: no_proxy.iter().map(|i| S!(*i)).collect(),
+        };
+        (
+            new_connector(&app_envs),
+            endpoint(&ws_address, &token_address),
+            ws_port,
+        )
+    }
+
+    fn requests(requests: &Requests) -> Vec<String> {
+        requests.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn connect_proxy_http() {
+        let (proxy, received) = http_proxy(r#"{"response":"abc"}"#).await;
+        // Only resolvable by the proxy
+        let (mut connector, endpoint, ws_port) =
+            proxy_connector(S!("http://token.invalid/token"), &proxy, &[]).await;
+
+        let mut socket = connector.ws_upgrade(&endpoint).await.unwrap();
+        socket.close(None).await.ok();
+        assert_eq!(
+            requests(&received),
+            [
+                S!("POST http://token.invalid/token HTTP/1.1"),
+                format!("CONNECT 127.0.0.1:{ws_port} HTTP/1.1")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_proxy_socks5() {
+        let (token_address, count) = token_server(200, S!(r#"{"response":"abc"}"#)).await;
+        let token_port = Url::parse(&token_address).unwrap().port().unwrap();
+        let (proxy, received) = socks_proxy().await;
+        let (mut connector, endpoint, ws_port) = proxy_connector(token_address, &proxy, &[]).await;
+
+        let mut socket = connector.ws_upgrade(&endpoint).await.unwrap();
+        socket.close(None).await.ok();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            requests(&received),
+            [
+                format!("127.0.0.1:{token_port}"),
+                format!("127.0.0.1:{ws_port}")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_no_proxy() {
+        let (token_address, count) = token_server(200, S!(r#"{"response":"abc"}"#)).await;
+        let (proxy, received) = socks_proxy().await;
+        let (mut connector, endpoint, _) =
+            proxy_connector(token_address, &proxy, &["127.0.0.1"]).await;
+
+        let mut socket = connector.ws_upgrade(&endpoint).await.unwrap();
+        socket.close(None).await.ok();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(requests(&received).is_empty());
+    }
+
+    #[tokio::test]
+    async fn connect_mutual_tls() {
+        let pki = TestPki::new("connect");
+        let token_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let token_port = token_listener.local_addr().unwrap().port();
+        let ws_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws_port = ws_listener.local_addr().unwrap().port();
+
+        let acceptor = pki.acceptor();
+        let token_request = tokio::spawn(async move {
+            let (stream, _) = token_listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let request = read_request(&mut stream).await;
+            let body = r#"{"response":"abc"}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.ok();
+            stream.shutdown().await.ok();
+            request
+        });
+        let acceptor = pki.acceptor();
+        tokio::spawn(async move {
+            let (stream, _) = ws_listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            futures_util::StreamExt::next(&mut socket).await;
+        });
+
+        let mut app_envs = test_setup();
+        app_envs.ws.as_mut().unwrap().password = None;
+        app_envs.tls = pki.config(&[&pki.server_spki]);
+        let mut connector = new_connector(&app_envs);
+        let endpoint = endpoint(
+            &format!("wss://localhost:{ws_port}"),
+            &format!("https://localhost:{token_port}/token"),
+        );
+
+        let mut socket = connector.ws_upgrade(&endpoint).await.unwrap();
+        socket.close(None).await.ok();
+        // No password, as authenticated by the client certificate
+        assert!(
+            token_request
+                .await
+                .unwrap()
+                .ends_with(r#"{"key":"ws_apikey"}"#)
+        );
+        pki.remove();
+    }
+
+    /// A server which accepts connections, but never responds
+    async fn silent_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                // Held open, without ever reading or writing
+                tokio::spawn(async move {
+                    let _stream = stream;
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+        port
+    }
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// A connector with short timeouts, and a cached token, so that only the WS connection is attempted
+    fn timeout_connector(ws_address: &str, proxy: Option<Proxy>) -> (Connector, Endpoint) {
+        let mut app_envs = test_setup();
+        app_envs.proxy.proxy = proxy;
+        app_envs.timeouts = TimeoutConfig {
+            token: TIMEOUT,
+            connect: TIMEOUT,
+            upgrade: TIMEOUT,
+        };
+        let mut connector = new_connector(&app_envs);
+        let endpoint = endpoint(ws_address, "ws_token_address");
+        connector.token = Some(CachedToken {
+            token: S!("abc"),
+            token_address: C!(endpoint.token_address),
+            expires: Instant::now() + Duration::from_secs(60),
+        });
+        (connector, endpoint)
+    }
+
+    #[tokio::test]
+    async fn connect_token_timeout() {
+        let port = silent_server().await;
+        let (mut connector, mut endpoint) = timeout_connector("ws://127.0.0.1:1", None);
+        connector.token = None;
+        endpoint.token_address = format!("http://127.0.0.1:{port}/token");
+
+        let start = Instant::now();
+        let result = connector.get_token(&endpoint).await;
+        assert!(matches!(result, Err(AppError::TokenTimeout(100))));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "token request timed out after 100ms"
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_tls_timeout() {
+        let port = silent_server().await;
+        let (mut connector, endpoint) = timeout_connector(&format!("wss://localhost:{port}"), None);
+        assert!(matches!(
+            connector.ws_upgrade(&endpoint).await,
+            Err(AppError::ConnectTimeout(100))
+        ));
+    }
+
+    #[tokio::test]
+    async fn connect_proxy_timeout() {
+        let port = silent_server().await;
+        let proxy = Proxy::parse(&format!("http://127.0.0.1:{port}")).unwrap();
+        let (mut connector, endpoint) = timeout_connector("ws://staticpi.com", Some(proxy));
+        assert!(matches!(
+            connector.ws_upgrade(&endpoint).await,
+            Err(AppError::ConnectTimeout(100))
+        ));
+    }
+
+    #[tokio::test]
+    async fn connect_upgrade_timeout() {
+        let port = silent_server().await;
+        let (mut connector, endpoint) = timeout_connector(&format!("ws://127.0.0.1:{port}"), None);
+        let start = Instant::now();
+        assert!(matches!(
+            connector.ws_upgrade(&endpoint).await,
+            Err(AppError::UpgradeTimeout(100))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn connect_probe() {
+        let (token_address, count) = token_server(200, S!(r#"{"response":"abc"}"#)).await;
+        let (ws_address, _) = ws_server().await;
+        let connector = new_connector(&test_setup());
+        assert!(
+            connector
+                .probe(&endpoint(&ws_address, &token_address))
+                .await
+                .is_ok()
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(
+            connector
+                .probe(&endpoint(&refused, &token_address))
+                .await
+                .is_err()
+        );
+        // Only the WS server is checked
+        assert_eq!(count.load(Ordering::SeqCst), 0);
     }
 }
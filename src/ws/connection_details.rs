@@ -1,64 +1,354 @@
-use std::time::Instant;
-use tracing::info;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::sleep;
+use jiff::Timestamp;
 
-#[derive(Debug)]
-pub struct ConnectionDetails {
-    count: usize,
-    wait: Wait,
-    connection_instant: Option<Instant>,
+use crate::{
+    C,
+    app_env::BackoffConfig,
+    app_error::AppError,
+    ws_messages::{ConnectionError, ConnectionState, ConnectionStatus},
+};
+
+/// Source of the current time, replaced in tests
+pub trait Clock {
+    fn now(&self) -> Instant;
 }
 
-#[derive(Debug)]
-enum Wait {
-    Short,
-    Long,
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
 }
 
-impl Wait {
-    const fn as_ms(&self) -> u64 {
-        match self {
-            Self::Short => 5_000,
-            Self::Long => 60_000,
-        }
+/// Source of randomness for the jitter, replaced in tests
+pub trait Random {
+    fn next_u64(&mut self) -> u64;
+}
+
+/// Xorshift64, more than enough to de-synchronise reconnects, without an extra dependency
+#[derive(Debug, Clone, Copy)]
+pub struct XorShift(u64);
+
+impl XorShift {
+    /// Seeded from the current time, and the process id, so that each device has a different sequence
+    pub fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |i| (i.as_secs() << 30) ^ u64::from(i.subsec_nanos()));
+        let seed = nanos ^ (u64::from(std::process::id()) << 32);
+        Self(seed.max(1))
     }
 }
 
+impl Random for XorShift {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Reconnect backoff, exponential with full jitter, each delay is a random duration between zero and
+/// `initial * 2^(attempt - 1)`, capped at `max`, so the first retry is within `initial`. The attempt count is reset once a
+/// connection has been open for `reset_after`. Also keeps the diagnostics included in the status, the connect count,
+/// consecutive failures, and the most recent error
+#[derive(Debug)]
+pub struct ConnectionDetails<C: Clock = SystemClock, R: Random = XorShift> {
+    attempt: u32,
+    clock: C,
+    config: BackoffConfig,
+    connection_instant: Option<Instant>,
+    connects: u64,
+    delay: Option<Duration>,
+    failures: u32,
+    last_connected_at: Option<i64>,
+    last_error: Option<ConnectionError>,
+    rng: R,
+    started: bool,
+    state: ConnectionState,
+}
+
 impl ConnectionDetails {
-    pub const fn new() -> Self {
+    pub fn new(config: BackoffConfig) -> Self {
+        Self::with(config, SystemClock, XorShift::new())
+    }
+}
+
+impl<C: Clock, R: Random> ConnectionDetails<C, R> {
+    const fn with(config: BackoffConfig, clock: C, rng: R) -> Self {
         Self {
-            count: 0,
-            wait: Wait::Short,
+            attempt: 0,
+            clock,
+            config,
             connection_instant: None,
+            connects: 0,
+            delay: None,
+            failures: 0,
+            last_connected_at: None,
+            last_error: None,
+            rng,
+            started: false,
+            state: ConnectionState::Disconnected,
         }
     }
 
-    /// increase attempt count, and set delay to long if 20+ attempts
-    /// Set is_connected to 0 and time to none
-    pub const fn fail_connect(&mut self) {
-        self.count += 1;
-        if self.count >= 20 {
-            self.wait = Wait::Long;
-        }
+    /// The upper bound of the next delay, `initial * 2^(attempt - 1)`, capped at max. The attempt has already been
+    /// incremented by the failure being retried, so the first retry is at most `initial`
+    fn ceiling(&self) -> Duration {
+        let initial = u64::try_from(self.config.initial.as_millis()).unwrap_or(u64::MAX);
+        let ceiling = initial.saturating_mul(2u64.saturating_pow(self.attempt.saturating_sub(1)));
+        Duration::from_millis(ceiling).min(self.config.max)
     }
 
-    /// delay the recconnect attempt by x seconds, depedning on ho wmany attempts already made
-    pub async fn reconnect_delay(&self) {
-        info!(self.count);
-        if self.count > 0 {
-            sleep!(self.wait.as_ms());
+    /// Calculate the delay before the next connection attempt, there is no delay before the very first attempt.
+    /// If a connection has just closed, then the attempt count is reset if it was open for long enough
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if let Some(connected) = self.connection_instant.take() {
+            if self.clock.now().saturating_duration_since(connected) >= self.config.reset_after {
+                self.attempt = 0;
+            } else {
+                self.attempt = self.attempt.saturating_add(1);
+            }
+        }
+        if !self.started {
+            self.started = true;
+            return None;
         }
+        let ceiling = u64::try_from(self.ceiling().as_millis()).unwrap_or(u64::MAX);
+        let delay = Duration::from_millis(self.rng.next_u64() % ceiling.saturating_add(1));
+        self.delay = Some(delay);
+        Some(delay)
+    }
+
+    pub const fn attempt(&self) -> u32 {
+        self.attempt
     }
 
-    /// called on each connect, to reset connection, count etc
+    pub const fn set_state(&mut self, state: ConnectionState) {
+        self.state = state;
+    }
+
+    /// Increase attempt count, so that the next delay ceiling is doubled, and record the error
+    pub fn fail_connect(&mut self, error: &AppError) {
+        self.attempt = self.attempt.saturating_add(1);
+        self.failures = self.failures.saturating_add(1);
+        self.last_error = Some(ConnectionError {
+            kind: error.kind().to_owned(),
+            message: error.to_string(),
+            at: Timestamp::now().as_second(),
+        });
+    }
+
+    /// Called on each connect, the attempt count is only reset once the connection is deemed stable
     pub fn valid_connect(&mut self) {
-        self.wait = Wait::Short;
-        self.count = 0;
-        self.connection_instant = Some(Instant::now());
+        self.connection_instant = Some(self.clock.now());
+        self.connects = self.connects.saturating_add(1);
+        self.failures = 0;
+        self.last_connected_at = Some(Timestamp::now().as_second());
         tracing::debug!(
             "{}",
             jiff::Zoned::now().timestamp().strftime("%Y-%m-%d %H:%M:%S")
         );
     }
+
+    /// Current backoff state, and diagnostics, for the status message
+    pub fn status(&self) -> ConnectionStatus {
+        ConnectionStatus {
+            state: self.state,
+            endpoint: None,
+            attempt: self.attempt,
+            delay_ms: self
+                .delay
+                .map(|i| u64::try_from(i.as_millis()).unwrap_or(u64::MAX)),
+            reconnects: self.connects.saturating_sub(1),
+            failures: self.failures,
+            last_error: C!(self.last_error),
+            last_connected_at: self.last_connected_at,
+        }
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test connection_details_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+pub mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    /// A clock which only moves when advanced
+    #[derive(Debug, Clone)]
+    pub struct MockClock(Arc<Mutex<Instant>>);
+
+    impl MockClock {
+        pub fn new() -> Self {
+            Self(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            if let Ok(mut now) = self.0.lock() {
+                *now += duration;
+            }
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.0.lock().map_or_else(|_| Instant::now(), |i| *i)
+        }
+    }
+
+    /// Returns each given value in turn, and then 0
+    struct MockRandom(VecDeque<u64>);
+
+    impl Random for MockRandom {
+        fn next_u64(&mut self) -> u64 {
+            self.0.pop_front().unwrap_or_default()
+        }
+    }
+
+    const CONFIG: BackoffConfig = BackoffConfig {
+        initial: Duration::from_secs(1),
+        max: Duration::from_secs(60),
+        reset_after: Duration::from_secs(30),
+    };
+
+    fn test_details(random: &[u64]) -> (ConnectionDetails<MockClock, MockRandom>, MockClock) {
+        let clock = MockClock::new();
+        let details = ConnectionDetails::with(
+            CONFIG,
+            clock.clone(),
+            MockRandom(random.iter().copied().collect()),
+        );
+        (details, clock)
+    }
+
+    #[test]
+    fn connection_details_first_attempt() {
+        let (mut details, _) = test_details(&[]);
+        assert!(details.next_delay().is_none());
+        assert_eq!(details.status().attempt, 0);
+        assert!(details.status().delay_ms.is_none());
+    }
+
+    #[test]
+    fn connection_details_exponential_ceiling() {
+        let (mut details, _) = test_details(&[]);
+        details.next_delay();
+        let mut ceilings = vec![details.ceiling().as_secs()];
+        for _ in 0..8 {
+            details.fail_connect(&AppError::WsStatus);
+            ceilings.push(details.ceiling().as_secs());
+        }
+        assert_eq!(ceilings, [1, 1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(details.status().attempt, 8);
+
+        for _ in 0..100 {
+            details.fail_connect(&AppError::WsStatus);
+        }
+        assert_eq!(details.ceiling(), CONFIG.max);
+    }
+
+    #[test]
+    fn connection_details_first_retry() {
+        // Every random value, so the largest possible first delay
+        let (mut details, _) = test_details(&[u64::MAX, 1_000, 999]);
+        details.next_delay();
+        details.fail_connect(&AppError::WsStatus);
+        for _ in 0..3 {
+            assert!(details.next_delay().unwrap() <= CONFIG.initial);
+        }
+        assert_eq!(details.status().delay_ms, Some(999));
+    }
+
+    #[test]
+    fn connection_details_full_jitter() {
+        let (mut details, _) = test_details(&[1_001, 500, 1_999, 2_001]);
+        details.next_delay();
+        details.fail_connect(&AppError::WsStatus);
+        // ceiling 1000ms, so delay is between 0 and 1000ms
+        assert_eq!(details.next_delay(), Some(Duration::ZERO));
+        assert_eq!(details.next_delay(), Some(Duration::from_millis(500)));
+        details.fail_connect(&AppError::WsStatus);
+        // ceiling 2000ms
+        assert_eq!(details.next_delay(), Some(Duration::from_millis(1_999)));
+        assert_eq!(details.next_delay(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn connection_details_stable_reset() {
+        let (mut details, clock) = test_details(&[]);
+        details.next_delay();
+        for _ in 0..5 {
+            details.fail_connect(&AppError::WsStatus);
+        }
+        details.valid_connect();
+        // Connected, but not yet stable, so the attempt count is kept
+        assert_eq!(details.status().attempt, 5);
+
+        clock.advance(CONFIG.reset_after);
+        details.next_delay();
+        assert_eq!(details.status().attempt, 0);
+        assert_eq!(details.ceiling(), CONFIG.initial);
+    }
+
+    #[test]
+    fn connection_details_unstable_connection() {
+        let (mut details, clock) = test_details(&[]);
+        details.next_delay();
+        details.fail_connect(&AppError::WsStatus);
+        details.valid_connect();
+        clock.advance(Duration::from_secs(5));
+        // Closed before reset_after, so treated as a failure
+        details.next_delay();
+        assert_eq!(details.status().attempt, 2);
+        assert_eq!(details.ceiling(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn connection_details_diagnostics() {
+        let (mut details, _) = test_details(&[]);
+        let status = details.status();
+        assert_eq!(status.reconnects, 0);
+        assert_eq!(status.failures, 0);
+        assert!(status.last_error.is_none());
+        assert!(status.last_connected_at.is_none());
+
+        details.fail_connect(&AppError::ConnectTimeout(10_000));
+        details.fail_connect(&AppError::WsStatus);
+        let status = details.status();
+        assert_eq!(status.failures, 2);
+        let last_error = status.last_error.unwrap();
+        assert_eq!(last_error.kind, "ws_status");
+        assert_eq!(last_error.message, "Invalid WS Status Code");
+        assert!(last_error.at > 0);
+
+        // The first connect isn't a reconnect, and the last error is kept
+        details.valid_connect();
+        let status = details.status();
+        assert_eq!(status.reconnects, 0);
+        assert_eq!(status.failures, 0);
+        assert!(status.last_connected_at.is_some());
+        assert!(status.last_error.is_some());
+
+        details.valid_connect();
+        details.valid_connect();
+        assert_eq!(details.status().reconnects, 2);
+    }
+
+    #[test]
+    fn connection_details_xorshift() {
+        let mut rng = XorShift(1);
+        let first = rng.next_u64();
+        let second = rng.next_u64();
+        assert_ne!(first, second);
+        assert_ne!(XorShift::new().next_u64(), 0);
+    }
 }
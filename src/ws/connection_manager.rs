@@ -0,0 +1,419 @@
+use async_channel::{Receiver, Sender};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    C,
+    app_env::{AppEnv, WsConfig},
+    app_error::AppError,
+    message_handler::Msg,
+    sd_notify::SdNotify,
+    ws::{ConnectionDetails, Connector},
+    ws_messages::{ConnectionState, ConnectionStatus},
+};
+
+use super::endpoints::Endpoints;
+
+/// Sent from the message handler to the connection manager
+#[derive(Debug)]
+pub enum ConnectionMsg {
+    /// The socket has been closed, a new connection should be opened
+    Closed,
+}
+
+/// Owns the WS connection lifecycle in its own task, so that neither a slow connection attempt, nor a backoff delay,
+/// block the message handler. Each state change is sent to the message handler, to be included in the status message
+#[derive(Debug)]
+pub struct ConnectionManager {
+    connection_details: ConnectionDetails,
+    connector: Connector,
+    endpoints: Endpoints,
+    notify: SdNotify,
+    rx: Receiver<ConnectionMsg>,
+    tx: Sender<Msg>,
+}
+
+impl ConnectionManager {
+    /// Returns the manager, and the sender used to tell it that the socket has closed
+    pub fn new(
+        app_env: &AppEnv,
+        ws: &WsConfig,
+        tx: &Sender<Msg>,
+        notify: &SdNotify,
+    ) -> Result<(Self, Sender<ConnectionMsg>), AppError> {
+        let (connection_tx, rx) = async_channel::bounded(1);
+        Ok((
+            Self {
+                connection_details: ConnectionDetails::new(app_env.backoff),
+                connector: Connector::new(app_env, ws)?,
+                endpoints: Endpoints::new(&ws.endpoints, app_env.failover),
+                notify: C!(notify),
+                rx,
+                tx: C!(tx),
+            },
+            connection_tx,
+        ))
+    }
+
+    /// Spawn the connection loop
+    pub fn start(self) {
+        tokio::spawn(self.run());
+    }
+
+    /// Update the state, and send the new connection status to the message handler
+    async fn set_state(&mut self, state: ConnectionState) {
+        self.connection_details.set_state(state);
+        let status = ConnectionStatus {
+            endpoint: Some(C!(self.endpoints.current().ws_address)),
+            ..self.connection_details.status()
+        };
+        self.tx.send(Msg::Connection(status)).await.ok();
+    }
+
+    /// Resolves once the primary endpoint has been healthy for long enough to fail back to, never if already using the primary
+    async fn failback(connector: &Connector, endpoints: &mut Endpoints) {
+        if !endpoints.should_probe() {
+            return std::future::pending().await;
+        }
+        let mut interval = tokio::time::interval(endpoints.probe_interval());
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick is immediate
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let result = connector.probe(endpoints.primary()).await;
+            if let Err(e) = &result {
+                debug!("primary endpoint probe failed: {e}");
+            }
+            if endpoints.probe(result.is_ok()) {
+                return;
+            }
+        }
+    }
+
+    /// Wait for the socket to close, or for a failback to the primary, in which case the message handler is asked to close the socket.
+    /// Returns false if the message handler has gone
+    async fn connected(&mut self) -> bool {
+        tokio::select! {
+            msg = self.rx.recv() => return msg.is_ok(),
+            () = Self::failback(&self.connector, &mut self.endpoints) => {
+                self.endpoints.failback();
+                info!(
+                    endpoint = self.endpoints.current().ws_address,
+                    "primary endpoint healthy, failing back"
+                );
+                self.tx.send(Msg::WsClose).await.ok();
+            }
+        }
+        self.rx.recv().await.is_ok()
+    }
+
+    /// Disconnected -> Backoff -> Connecting -> Connected, and back to Disconnected once the socket closes.
+    /// A failed connection attempt, including an unauthorized token request, moves on to the next endpoint.
+    /// Once every endpoint has rejected the credentials the loop ends, as the api key or password needs to be changed
+    async fn run(mut self) {
+        loop {
+            if let Some(delay) = self.connection_details.next_delay() {
+                self.set_state(ConnectionState::Backoff).await;
+                let delay_ms = u64::try_from(delay.as_millis()).unwrap_or(u64::MAX);
+                info!(
+                    ws_state = "backoff",
+                    attempt = self.connection_details.attempt(),
+                    delay_ms,
+                    "reconnecting in {delay_ms}ms"
+                );
+                self.notify
+                    .status(&format!("reconnecting to WS server in {delay_ms}ms"));
+                tokio::time::sleep(delay).await;
+            }
+
+            let endpoint = C!(self.endpoints.current());
+            self.set_state(ConnectionState::Connecting).await;
+            info!(
+                ws_state = "connecting",
+                endpoint = endpoint.ws_address,
+                "connecting to WS server"
+            );
+            self.notify.status("connecting to WS server");
+
+            match self.connector.ws_upgrade(&endpoint).await {
+                Ok(stream) => {
+                    self.connection_details.valid_connect();
+                    self.endpoints.accepted();
+                    self.set_state(ConnectionState::Connected).await;
+                    info!(
+                        ws_state = "connected",
+                        endpoint = endpoint.ws_address,
+                        "connected to WS server"
+                    );
+                    self.notify
+                        .status(&format!("connected to WS server {}", endpoint.ws_address));
+                    self.tx.send(Msg::WsConnected(Box::new(stream))).await.ok();
+                    if !self.connected().await {
+                        return;
+                    }
+                    self.set_state(ConnectionState::Disconnected).await;
+                    info!(ws_state = "disconnected", "disconnected from WS server");
+                    self.notify.status("disconnected from WS server");
+                }
+                Err(e @ AppError::TokenUnauthorized(..)) => {
+                    self.connection_details.fail_connect(&e);
+                    self.set_state(ConnectionState::Disconnected).await;
+                    self.endpoints.reject();
+                    if !self.endpoints.all_rejected() {
+                        warn!(
+                            ws_state = "unauthorized",
+                            endpoint = endpoint.ws_address,
+                            "{e}, failing over to the next endpoint"
+                        );
+                        continue;
+                    }
+                    error!(
+                        ws_state = "unauthorized",
+                        "{e}, rejected by every endpoint, not reconnecting until the credentials are fixed, and the daemon restarted"
+                    );
+                    self.notify.status(
+                        "WS credentials rejected, not reconnecting, check WS_APIKEY & WS_PASSWORD",
+                    );
+                    return;
+                }
+                Err(e) => {
+                    error!(endpoint = endpoint.ws_address, "connection::{e}");
+                    self.connection_details.fail_connect(&e);
+                    self.set_state(ConnectionState::Disconnected).await;
+                    self.endpoints.fail();
+                }
+            }
+        }
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test connection_manager_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::StreamExt;
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    use super::*;
+    use crate::{
+        S,
+        app_env::{BackoffConfig, Endpoint, FailoverConfig},
+        message_handler::WsStream,
+        tests::test_setup,
+        ws::connect::tests::{token_server, ws_server},
+    };
+
+    /// An endpoint with a stand-in token server, which responds with the given status, and a stand-in WS server if no address is given
+    async fn test_endpoint(status: u16, ws_address: Option<String>) -> Endpoint {
+        let (token_address, _) = token_server(status, S!(r#"{"response":"abc"}"#)).await;
+        Endpoint {
+            ws_address: match ws_address {
+                Some(address) => address,
+                None => ws_server().await.0,
+            },
+            token_address,
+        }
+    }
+
+    /// A manager with a 1ms backoff, and a quick failback
+    fn test_manager(
+        endpoints: Vec<Endpoint>,
+    ) -> (ConnectionManager, Sender<ConnectionMsg>, Receiver<Msg>) {
+        let mut app_envs = test_setup();
+        let mut ws = app_envs.ws.take().unwrap();
+        ws.endpoints = endpoints;
+        app_envs.failover = FailoverConfig {
+            probe_interval: Duration::from_millis(20),
+            failback_after: Duration::from_millis(100),
+        };
+        app_envs.backoff = BackoffConfig {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(1),
+            reset_after: Duration::from_secs(30),
+        };
+        let (tx, rx) = async_channel::bounded(16);
+        let (manager, connection_tx) =
+            ConnectionManager::new(&app_envs, &ws, &tx, &SdNotify::from_env()).unwrap();
+        (manager, connection_tx, rx)
+    }
+
+    async fn next(rx: &Receiver<Msg>) -> Msg {
+        tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn next_status(rx: &Receiver<Msg>) -> ConnectionStatus {
+        match next(rx).await {
+            Msg::Connection(status) => status,
+            msg => panic!("expected a connection status, got {msg:?}"),
+        }
+    }
+
+    /// Skip over each status until connected, returns the final status, and the stream
+    async fn connected(rx: &Receiver<Msg>) -> (ConnectionStatus, Box<WsStream>) {
+        let mut status = None;
+        loop {
+            match next(rx).await {
+                Msg::Connection(i) => status = Some(i),
+                Msg::WsConnected(stream) => return (status.unwrap(), stream),
+                msg => panic!("unexpected {msg:?}"),
+            }
+        }
+    }
+
+    /// A stand-in WS server which can be killed, and then restarted on the same port
+    async fn killable_ws_server(port: u16) -> (u16, JoinHandle<()>) {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if let Ok(mut socket) = tokio_tungstenite::accept_async(stream).await {
+                        socket.next().await;
+                    }
+                });
+            }
+        });
+        (port, handle)
+    }
+
+    #[tokio::test]
+    async fn connection_manager_reconnect() {
+        let endpoint = test_endpoint(200, None).await;
+        let (manager, connection_tx, rx) = test_manager(vec![C!(endpoint)]);
+        manager.start();
+
+        let status = next_status(&rx).await;
+        assert_eq!(status.state, ConnectionState::Connecting);
+        assert_eq!(status.endpoint, Some(endpoint.ws_address));
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Connected);
+        assert!(matches!(next(&rx).await, Msg::WsConnected(_)));
+
+        connection_tx.send(ConnectionMsg::Closed).await.unwrap();
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Disconnected);
+        let status = next_status(&rx).await;
+        assert_eq!(status.state, ConnectionState::Backoff);
+        assert_eq!(status.attempt, 1);
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Connecting);
+        let status = next_status(&rx).await;
+        assert_eq!(status.state, ConnectionState::Connected);
+        assert_eq!(status.reconnects, 1);
+        assert!(status.last_connected_at.is_some());
+        assert!(matches!(next(&rx).await, Msg::WsConnected(_)));
+    }
+
+    #[tokio::test]
+    async fn connection_manager_outage() {
+        // Nothing listening on the ws address
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws_address = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let (manager, _connection_tx, rx) =
+            test_manager(vec![test_endpoint(200, Some(ws_address)).await]);
+        manager.start();
+
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Connecting);
+        for attempt in 1..=3 {
+            let status = next_status(&rx).await;
+            assert_eq!(status.state, ConnectionState::Disconnected);
+            assert_eq!(status.attempt, attempt);
+            assert_eq!(status.failures, attempt);
+            assert_eq!(status.last_error.unwrap().kind, "io");
+            assert_eq!(next_status(&rx).await.state, ConnectionState::Backoff);
+            assert_eq!(next_status(&rx).await.state, ConnectionState::Connecting);
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_manager_unauthorized() {
+        let (manager, _connection_tx, rx) = test_manager(vec![test_endpoint(401, None).await]);
+        manager.start();
+
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Connecting);
+        let status = next_status(&rx).await;
+        assert_eq!(status.state, ConnectionState::Disconnected);
+        assert_eq!(status.last_error.unwrap().kind, "token_unauthorized");
+        // The manager has stopped, and so dropped its sender
+        assert!(
+            tokio::time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_manager_unauthorized_failover() {
+        let primary = test_endpoint(401, None).await;
+        let standby = test_endpoint(200, None).await;
+        let (manager, _connection_tx, rx) = test_manager(vec![C!(primary), C!(standby)]);
+        manager.start();
+
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Connecting);
+        let status = next_status(&rx).await;
+        assert_eq!(status.state, ConnectionState::Disconnected);
+        assert_eq!(status.last_error.unwrap().kind, "token_unauthorized");
+        let (status, _stream) = connected(&rx).await;
+        assert_eq!(status.endpoint, Some(standby.ws_address));
+
+        // Every endpoint rejects the credentials, so the manager stops
+        let (manager, _connection_tx, rx) = test_manager(vec![
+            test_endpoint(401, None).await,
+            test_endpoint(403, None).await,
+        ]);
+        manager.start();
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Connecting);
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Disconnected);
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Backoff);
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Connecting);
+        assert_eq!(next_status(&rx).await.state, ConnectionState::Disconnected);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_manager_failover() {
+        let (primary_port, primary_server) = killable_ws_server(0).await;
+        let primary = test_endpoint(200, Some(format!("ws://127.0.0.1:{primary_port}"))).await;
+        let standby = test_endpoint(200, None).await;
+        let (manager, connection_tx, rx) = test_manager(vec![C!(primary), C!(standby)]);
+        manager.start();
+
+        let (status, stream) = connected(&rx).await;
+        assert_eq!(status.endpoint, Some(C!(primary.ws_address)));
+
+        // Kill the primary, so the reconnect fails over to the standby
+        primary_server.abort();
+        primary_server.await.ok();
+        drop(stream);
+        connection_tx.send(ConnectionMsg::Closed).await.unwrap();
+        let (status, stream) = connected(&rx).await;
+        assert_eq!(status.state, ConnectionState::Connected);
+        assert_eq!(status.endpoint, Some(C!(standby.ws_address)));
+
+        // Whilst the primary is down, the standby connection is left alone
+        assert!(
+            tokio::time::timeout(Duration::from_millis(250), rx.recv())
+                .await
+                .is_err()
+        );
+
+        // Once the primary has been healthy for long enough, the standby connection is closed
+        let (_, primary_server) = killable_ws_server(primary_port).await;
+        assert!(matches!(next(&rx).await, Msg::WsClose));
+        drop(stream);
+        connection_tx.send(ConnectionMsg::Closed).await.unwrap();
+        let (status, _stream) = connected(&rx).await;
+        assert_eq!(status.endpoint, Some(primary.ws_address));
+        primary_server.abort();
+    }
+}
@@ -0,0 +1,197 @@
+use std::time::{Duration, Instant};
+
+use crate::app_env::{Endpoint, FailoverConfig};
+
+use super::connection_details::{Clock, SystemClock};
+
+/// The ordered endpoints, the first is the primary, and there is always at least one. A failed connection attempt moves on
+/// to the next endpoint, wrapping back round to the primary. Whilst connected to a standby the primary is probed, and once
+/// it has been healthy for `failback_after` the connection fails back to it. An endpoint whose token server rejects the
+/// credentials is marked as rejected, until it next accepts them, and isn't failed back to
+#[derive(Debug)]
+pub struct Endpoints<C: Clock = SystemClock> {
+    clock: C,
+    config: FailoverConfig,
+    current: usize,
+    healthy_since: Option<Instant>,
+    list: Vec<Endpoint>,
+    rejected: Vec<bool>,
+}
+
+impl Endpoints {
+    pub fn new(endpoints: &[Endpoint], config: FailoverConfig) -> Self {
+        Self::with(endpoints, config, SystemClock)
+    }
+}
+
+impl<C: Clock> Endpoints<C> {
+    fn with(endpoints: &[Endpoint], config: FailoverConfig, clock: C) -> Self {
+        Self {
+            clock,
+            config,
+            current: 0,
+            healthy_since: None,
+            list: endpoints.to_vec(),
+            rejected: vec![false; endpoints.len()],
+        }
+    }
+
+    /// The endpoint to use for the next connection attempt
+    pub fn current(&self) -> &Endpoint {
+        &self.list[self.current]
+    }
+
+    pub fn primary(&self) -> &Endpoint {
+        &self.list[0]
+    }
+
+    pub const fn is_primary(&self) -> bool {
+        self.current == 0
+    }
+
+    /// Whether to probe the primary, only if using a standby, and the primary hasn't rejected the credentials
+    pub fn should_probe(&self) -> bool {
+        !self.is_primary() && !self.rejected[0]
+    }
+
+    pub const fn probe_interval(&self) -> Duration {
+        self.config.probe_interval
+    }
+
+    /// The current endpoint couldn't be connected to, so move on to the next
+    pub const fn fail(&mut self) {
+        self.current = (self.current + 1) % self.list.len();
+        self.healthy_since = None;
+    }
+
+    /// The current endpoint rejected the credentials, so mark it, and move on to the next
+    pub fn reject(&mut self) {
+        self.rejected[self.current] = true;
+        self.fail();
+    }
+
+    /// Every endpoint has rejected the credentials, so there's nothing left to fail over to
+    pub fn all_rejected(&self) -> bool {
+        self.rejected.iter().all(|i| *i)
+    }
+
+    /// The current endpoint accepted the credentials
+    pub fn accepted(&mut self) {
+        self.rejected[self.current] = false;
+    }
+
+    /// Record the result of probing the primary, returns true once it has been healthy for long enough to fail back to
+    pub fn probe(&mut self, healthy: bool) -> bool {
+        if !healthy {
+            self.healthy_since = None;
+            return false;
+        }
+        let now = self.clock.now();
+        let since = *self.healthy_since.get_or_insert(now);
+        now.saturating_duration_since(since) >= self.config.failback_after
+    }
+
+    /// Use the primary for the next connection attempt
+    pub const fn failback(&mut self) {
+        self.current = 0;
+        self.healthy_since = None;
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test endpoints_ -- --nocapture'
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{S, ws::connection_details::tests::MockClock};
+
+    fn test_endpoints() -> (Endpoints<MockClock>, MockClock) {
+        let endpoint = |name: &str| Endpoint {
+            ws_address: format!("wss://{name}"),
+            token_address: format!("https://{name}/token"),
+        };
+        let clock = MockClock::new();
+        let endpoints = Endpoints::with(
+            &[endpoint("primary"), endpoint("standby")],
+            FailoverConfig {
+                probe_interval: Duration::from_secs(30),
+                failback_after: Duration::from_secs(300),
+            },
+            clock.clone(),
+        );
+        (endpoints, clock)
+    }
+
+    #[test]
+    fn endpoints_fail() {
+        let (mut endpoints, _) = test_endpoints();
+        assert!(endpoints.is_primary());
+        assert_eq!(endpoints.current().ws_address, "wss://primary");
+
+        endpoints.fail();
+        assert!(!endpoints.is_primary());
+        assert_eq!(endpoints.current().ws_address, "wss://standby");
+        assert_eq!(endpoints.current().token_address, "https://standby/token");
+        assert_eq!(endpoints.primary().ws_address, "wss://primary");
+
+        // Wraps back round to the primary
+        endpoints.fail();
+        assert!(endpoints.is_primary());
+
+        // A single endpoint is always current
+        let mut endpoints = Endpoints::new(
+            &[Endpoint {
+                ws_address: S!("wss://only"),
+                token_address: S!("https://only/token"),
+            }],
+            endpoints.config,
+        );
+        endpoints.fail();
+        assert!(endpoints.is_primary());
+    }
+
+    #[test]
+    fn endpoints_failback() {
+        let (mut endpoints, clock) = test_endpoints();
+        endpoints.fail();
+
+        assert!(!endpoints.probe(true));
+        clock.advance(Duration::from_secs(299));
+        assert!(!endpoints.probe(true));
+        clock.advance(Duration::from_secs(1));
+        assert!(endpoints.probe(true));
+
+        // An unhealthy probe restarts the window
+        assert!(!endpoints.probe(false));
+        assert!(!endpoints.probe(true));
+        clock.advance(Duration::from_secs(200));
+        assert!(!endpoints.probe(true));
+        clock.advance(Duration::from_secs(100));
+        assert!(endpoints.probe(true));
+
+        endpoints.failback();
+        assert!(endpoints.is_primary());
+        assert!(!endpoints.probe(true));
+    }
+
+    #[test]
+    fn endpoints_reject() {
+        let (mut endpoints, _) = test_endpoints();
+        assert!(!endpoints.should_probe());
+
+        endpoints.reject();
+        assert_eq!(endpoints.current().ws_address, "wss://standby");
+        assert!(!endpoints.all_rejected());
+        // The primary rejected the credentials, so isn't failed back to
+        assert!(!endpoints.should_probe());
+
+        endpoints.reject();
+        assert!(endpoints.all_rejected());
+
+        // Accepted once again
+        endpoints.accepted();
+        assert!(!endpoints.all_rejected());
+        endpoints.fail();
+        endpoints.accepted();
+        assert!(endpoints.should_probe());
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::ws_messages::Rtt;
+
+use super::{
+    connect::millis,
+    connection_details::{Clock, SystemClock},
+};
+
+/// Number of round trip times used for the average
+const RTT_SAMPLES: usize = 10;
+
+/// Client side pings, each carries a sequence number as its payload, so that the matching pong gives the round trip time.
+/// The connection is deemed dead once `max_missed` pings in a row have gone unanswered
+#[derive(Debug)]
+pub struct Heartbeat<C: Clock = SystemClock> {
+    clock: C,
+    max_missed: u32,
+    missed: u32,
+    pending: Option<(u64, Instant)>,
+    samples: VecDeque<Duration>,
+    sequence: u64,
+}
+
+impl Heartbeat {
+    pub const fn new(max_missed: u32) -> Self {
+        Self::with(max_missed, SystemClock)
+    }
+}
+
+impl<C: Clock> Heartbeat<C> {
+    const fn with(max_missed: u32, clock: C) -> Self {
+        Self {
+            clock,
+            max_missed,
+            missed: 0,
+            pending: None,
+            samples: VecDeque::new(),
+            sequence: 0,
+        }
+    }
+
+    pub const fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    /// The payload of the next ping, or None if too many pings have gone unanswered, and the connection should be closed
+    pub fn ping(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_some() {
+            self.missed = self.missed.saturating_add(1);
+        }
+        if self.missed >= self.max_missed {
+            return None;
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.pending = Some((self.sequence, self.clock.now()));
+        Some(self.sequence.to_be_bytes().to_vec())
+    }
+
+    /// Any pong shows that the connection is alive, but only the pong for the most recent ping gives a round trip time
+    pub fn pong(&mut self, payload: &[u8]) {
+        self.missed = 0;
+        if let Some((sequence, sent)) = self.pending
+            && payload == sequence.to_be_bytes()
+        {
+            self.pending = None;
+            if self.samples.len() == RTT_SAMPLES {
+                self.samples.pop_front();
+            }
+            self.samples
+                .push_back(self.clock.now().saturating_duration_since(sent));
+        }
+    }
+
+    /// The most recent round trip time, and the average of the last few, None until the first pong
+    pub fn rtt(&self) -> Option<Rtt> {
+        let latest = self.samples.back()?;
+        let count = u32::try_from(self.samples.len()).unwrap_or(u32::MAX);
+        let average = self.samples.iter().sum::<Duration>() / count;
+        Some(Rtt {
+            latest_ms: millis(*latest),
+            average_ms: millis(average),
+        })
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test heartbeat_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::ws::connection_details::tests::MockClock;
+
+    fn test_heartbeat(max_missed: u32) -> (Heartbeat<MockClock>, MockClock) {
+        let clock = MockClock::new();
+        (Heartbeat::with(max_missed, clock.clone()), clock)
+    }
+
+    #[test]
+    fn heartbeat_rtt() {
+        let (mut heartbeat, clock) = test_heartbeat(3);
+        assert!(heartbeat.rtt().is_none());
+
+        for ms in [20, 40, 90] {
+            let payload = heartbeat.ping().unwrap();
+            clock.advance(Duration::from_millis(ms));
+            heartbeat.pong(&payload);
+        }
+        assert_eq!(
+            heartbeat.rtt(),
+            Some(Rtt {
+                latest_ms: 90,
+                average_ms: 50,
+            })
+        );
+
+        // Only the last RTT_SAMPLES are averaged
+        for _ in 0..RTT_SAMPLES {
+            let payload = heartbeat.ping().unwrap();
+            clock.advance(Duration::from_millis(10));
+            heartbeat.pong(&payload);
+        }
+        assert_eq!(
+            heartbeat.rtt(),
+            Some(Rtt {
+                latest_ms: 10,
+                average_ms: 10,
+            })
+        );
+    }
+
+    #[test]
+    fn heartbeat_stale_pong() {
+        let (mut heartbeat, clock) = test_heartbeat(3);
+        let stale = heartbeat.ping().unwrap();
+        let payload = heartbeat.ping().unwrap();
+        assert_ne!(stale, payload);
+        assert_eq!(heartbeat.missed(), 1);
+
+        // A late pong still counts as being alive, but isn't a round trip time for the current ping
+        clock.advance(Duration::from_millis(30));
+        heartbeat.pong(&stale);
+        assert_eq!(heartbeat.missed(), 0);
+        assert!(heartbeat.rtt().is_none());
+
+        // Nor is an unsolicited pong
+        heartbeat.pong(&[]);
+        assert!(heartbeat.rtt().is_none());
+
+        heartbeat.pong(&payload);
+        assert_eq!(heartbeat.rtt().unwrap().latest_ms, 30);
+    }
+
+    #[test]
+    fn heartbeat_missed() {
+        let (mut heartbeat, _) = test_heartbeat(3);
+        assert!(heartbeat.ping().is_some());
+        assert!(heartbeat.ping().is_some());
+        assert!(heartbeat.ping().is_some());
+        assert_eq!(heartbeat.missed(), 2);
+        assert!(heartbeat.ping().is_none());
+        assert_eq!(heartbeat.missed(), 3);
+
+        // Any pong before the limit resets the count
+        let (mut heartbeat, _) = test_heartbeat(2);
+        heartbeat.ping();
+        heartbeat.ping();
+        heartbeat.pong(&[]);
+        assert_eq!(heartbeat.missed(), 0);
+        assert!(heartbeat.ping().is_some());
+        assert!(heartbeat.ping().is_none());
+    }
+}
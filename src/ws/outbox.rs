@@ -0,0 +1,356 @@
+use std::{collections::VecDeque, path::Path};
+
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    app_env::OutboxConfig,
+    ws_messages::{Response, StructuredResponse},
+};
+
+use super::Socket;
+
+/// A response which couldn't be sent, and the unix timestamp, in ms, of when it was queued
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Queued {
+    pub queued_at_ms: i64,
+    pub response: Response,
+}
+
+impl Queued {
+    /// The WS message, which includes when the response was queued
+    pub fn message(&self) -> Message {
+        StructuredResponse::queued(self.response.clone(), self.queued_at_ms)
+    }
+}
+
+/// Responses queued whilst the WS is disconnected, to be sent, in order, once reconnected. A status is only a snapshot, so
+/// only the latest is kept, and sent last, but every other response, such as a screen change, is a discrete event, and
+/// all are kept. Once full the oldest event is dropped. If a path is given the outbox is persisted, so that it survives a
+/// restart of the daemon
+#[derive(Debug)]
+pub struct Outbox {
+    config: OutboxConfig,
+    events: VecDeque<Queued>,
+    status: Option<Queued>,
+}
+
+impl Outbox {
+    /// Load any persisted responses, a missing, or invalid, file is an empty outbox
+    pub fn new(config: OutboxConfig) -> Self {
+        let queued = config
+            .path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|data| serde_json::from_str::<Vec<Queued>>(&data).ok())
+            .unwrap_or_default();
+        let mut outbox = Self {
+            config,
+            events: VecDeque::new(),
+            status: None,
+        };
+        for queued in queued {
+            outbox.insert(queued);
+        }
+        if outbox.len() > 0 {
+            tracing::info!(queued = outbox.len(), "outbox loaded");
+        }
+        outbox
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len() + usize::from(self.status.is_some())
+    }
+
+    pub fn front(&self) -> Option<&Queued> {
+        self.events.front().or(self.status.as_ref())
+    }
+
+    /// Queue a status, replacing any queued status, or append an event, dropping the oldest once full
+    fn insert(&mut self, queued: Queued) {
+        if self.config.size == 0 {
+            return;
+        }
+        if matches!(queued.response, Response::Status(_)) {
+            self.status = Some(queued);
+            return;
+        }
+        while self.events.len() >= self.config.size {
+            self.events.pop_front();
+            tracing::warn!(
+                size = self.config.size,
+                "outbox full, oldest response dropped"
+            );
+        }
+        self.events.push_back(queued);
+    }
+
+    /// Queue a response, and persist the outbox
+    pub async fn push(&mut self, response: Response) {
+        if self.config.size == 0 {
+            return;
+        }
+        self.insert(Queued {
+            queued_at_ms: Timestamp::now().as_millisecond(),
+            response,
+        });
+        self.persist().await;
+    }
+
+    /// Remove the front response, once it has been sent
+    pub fn pop(&mut self) {
+        if self.events.pop_front().is_none() {
+            self.status = None;
+        }
+    }
+
+    /// Send, in order, every queued response, stopping at the first which can't be sent, which remains queued.
+    /// Returns the number sent
+    pub async fn flush(&mut self, socket: &mut Socket) -> usize {
+        let mut sent = 0;
+        while let Some(queued) = self.front() {
+            if !socket.send(queued.message()).await {
+                break;
+            }
+            self.pop();
+            sent += 1;
+        }
+        if sent > 0 {
+            self.persist().await;
+        }
+        sent
+    }
+
+    /// Write the outbox to disk, an empty outbox removes the file
+    pub async fn persist(&self) {
+        let Some(path) = &self.config.path else {
+            return;
+        };
+        let result = if self.len() == 0 {
+            match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        } else {
+            let queued = self.events.iter().chain(&self.status).collect::<Vec<_>>();
+            match serde_json::to_string(&queued) {
+                Ok(data) => Self::write(path, data).await,
+                Err(e) => Err(std::io::Error::other(e)),
+            }
+        };
+        if let Err(e) = result {
+            tracing::error!(path = %path.display(), "unable to persist the outbox: {e}");
+        }
+    }
+
+    /// Write to a temporary file, and then rename it, so that a crash mid write can't leave a partial file
+    async fn write(path: &Path, data: String) -> std::io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test outbox_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use futures_util::StreamExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        C, S,
+        app_env::HeartbeatConfig,
+        ws_messages::{PiStatus, ScreenEvent, ScreenStatus},
+    };
+
+    fn test_outbox(size: usize, path: Option<PathBuf>) -> Outbox {
+        Outbox::new(OutboxConfig { size, path })
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "screen_control_test_outbox_{name}_{}.json",
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    fn status(version: &str) -> Response {
+        Response::Status(Box::new(PiStatus {
+            connection: None,
+            ip_address: S!("192.168.1.2"),
+            revert: None,
+            rtt: None,
+            screen_status: Some(ScreenStatus::On),
+            time_off: (21, 0),
+            time_on: (8, 5),
+            timezone: S!("Europe/London"),
+            uptime_app: 0,
+            uptime_ws: 0,
+            uptime: 0,
+            version: S!(version),
+        }))
+    }
+
+    fn screen(screen_status: ScreenStatus) -> Response {
+        Response::ScreenChange(ScreenEvent {
+            screen_status,
+            source: S!("schedule"),
+        })
+    }
+
+    fn describe(response: &Response) -> String {
+        match response {
+            Response::Status(status) => format!("status {}", status.version),
+            Response::ScreenChange(event) => format!("screen {}", event.screen_status),
+            Response::Error(e) => format!("error {e}"),
+        }
+    }
+
+    fn drain(outbox: &mut Outbox) -> Vec<String> {
+        let mut sent = vec![];
+        while let Some(queued) = outbox.front() {
+            sent.push(describe(&queued.response));
+            outbox.pop();
+        }
+        sent
+    }
+
+    /// A stand-in WS server, which forwards each text message it receives
+    async fn test_socket() -> (Socket, async_channel::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("ws://{}", listener.local_addr().unwrap());
+        let (received_tx, received_rx) = async_channel::unbounded();
+        tokio::spawn(async move {
+            if let Ok((stream, _)) = listener.accept().await
+                && let Ok(mut socket) = tokio_tungstenite::accept_async(stream).await
+            {
+                while let Some(Ok(message)) = socket.next().await {
+                    if let Message::Text(text) = message {
+                        received_tx.send(text.to_string()).await.ok();
+                    }
+                }
+            }
+        });
+        let (stream, _) = tokio_tungstenite::connect_async(address).await.unwrap();
+        let (tx, _) = async_channel::bounded(16);
+        let config = HeartbeatConfig {
+            interval: None,
+            missed: 3,
+            auto_close: None,
+        };
+        (Socket::new(Box::new(stream), &tx, config), received_rx)
+    }
+
+    #[tokio::test]
+    async fn outbox_dedup() {
+        let mut outbox = test_outbox(10, None);
+        outbox.push(status("1")).await;
+        outbox.push(screen(ScreenStatus::On)).await;
+        outbox.push(status("2")).await;
+        outbox.push(Response::Error(S!("a"))).await;
+        outbox.push(screen(ScreenStatus::Off)).await;
+        outbox.push(status("3")).await;
+        assert_eq!(outbox.len(), 4);
+        assert_eq!(
+            drain(&mut outbox),
+            ["screen on", "error a", "screen off", "status 3"]
+        );
+        assert!(outbox.front().is_none());
+    }
+
+    #[tokio::test]
+    async fn outbox_bounded() {
+        let mut outbox = test_outbox(2, None);
+        for e in ["a", "b", "c"] {
+            outbox.push(Response::Error(S!(e))).await;
+        }
+        // The latest status is kept, even once full of events
+        outbox.push(status("1")).await;
+        assert_eq!(drain(&mut outbox), ["error b", "error c", "status 1"]);
+
+        let mut outbox = test_outbox(0, None);
+        outbox.push(Response::Error(S!("a"))).await;
+        outbox.push(status("1")).await;
+        assert_eq!(outbox.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn outbox_persist() {
+        let path = test_path("persist");
+
+        let mut outbox = test_outbox(10, Some(C!(path)));
+        outbox.push(status("1")).await;
+        outbox.push(Response::Error(S!("a"))).await;
+        let queued_at_ms = outbox.front().unwrap().queued_at_ms;
+        assert!(path.exists());
+        assert!(!path.with_extension("json.tmp").exists());
+
+        let mut outbox = test_outbox(10, Some(C!(path)));
+        assert_eq!(outbox.front().unwrap().queued_at_ms, queued_at_ms);
+        assert_eq!(drain(&mut outbox), ["error a", "status 1"]);
+        outbox.persist().await;
+        assert!(!path.exists());
+
+        // A smaller outbox keeps the newest of the persisted responses
+        let mut outbox = test_outbox(10, Some(C!(path)));
+        for e in ["a", "b", "c"] {
+            outbox.push(Response::Error(S!(e))).await;
+        }
+        let mut outbox = test_outbox(2, Some(C!(path)));
+        assert_eq!(drain(&mut outbox), ["error b", "error c"]);
+        outbox.persist().await;
+    }
+
+    #[tokio::test]
+    async fn outbox_replay() {
+        let path = test_path("replay");
+
+        // Scheduled changes, and their statuses, whilst disconnected, and then a restart of the daemon
+        let mut outbox = test_outbox(10, Some(C!(path)));
+        for (change, version) in [
+            (ScreenStatus::Off, "1"),
+            (ScreenStatus::On, "2"),
+            (ScreenStatus::Off, "3"),
+        ] {
+            outbox.push(screen(change)).await;
+            outbox.push(status(version)).await;
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        let mut outbox = test_outbox(10, Some(C!(path)));
+
+        // Once reconnected every change is sent, in order, each with when it was queued, and then the latest status
+        let (mut socket, received) = test_socket().await;
+        assert_eq!(outbox.flush(&mut socket).await, 4);
+        assert_eq!(outbox.len(), 0);
+        assert!(!path.exists());
+
+        let mut replayed = vec![];
+        let mut queued_at = vec![];
+        for _ in 0..4 {
+            let text = tokio::time::timeout(Duration::from_secs(2), received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let value = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+            queued_at.push(value["queued_at_ms"].as_i64().unwrap());
+            replayed.push(describe(
+                &serde_json::from_value::<Response>(value["data"].clone()).unwrap(),
+            ));
+        }
+        assert_eq!(
+            replayed,
+            ["screen off", "screen on", "screen off", "status 3"]
+        );
+        assert!(queued_at.is_sorted());
+        assert!(queued_at[0] < queued_at[2]);
+        socket.close().await;
+    }
+}
@@ -1,49 +1,89 @@
+use std::time::Duration;
+
 use async_channel::Sender;
 use futures_util::{SinkExt, StreamExt, TryStreamExt};
+use tokio::time::MissedTickBehavior;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
     C,
+    app_env::HeartbeatConfig,
     message_handler::{Msg, WSReader, WSWriter, WsStream},
-    sleep,
-    ws_messages::{Response, StructuredResponse},
+    ws_messages::Rtt,
 };
 
+use super::heartbeat::Heartbeat;
+
 #[derive(Debug)]
 pub struct Socket {
     writer: WSWriter,
     incoming_msg_token: CancellationToken,
+    auto_close: Option<Duration>,
     auto_close_token: CancellationToken,
+    heartbeat: Heartbeat,
+    heartbeat_token: CancellationToken,
 }
 
 impl Socket {
-    /// Split the stream into reader and writer, and spawn threads for incoming messages & an autocloser
-    pub fn new(stream: Box<WsStream>, tx: &Sender<Msg>) -> Self {
+    /// Split the stream into reader and writer, and spawn threads for incoming messages, an autocloser, and the client pings
+    pub fn new(stream: Box<WsStream>, tx: &Sender<Msg>, config: HeartbeatConfig) -> Self {
         let (writer, reader) = stream.split();
         Self {
             incoming_msg_token: Self::start_incoming_msg_thread(reader, tx),
-            auto_close_token: Self::start_auto_close(tx),
+            auto_close: config.auto_close,
+            auto_close_token: Self::start_auto_close(config.auto_close, tx),
+            heartbeat: Heartbeat::new(config.missed),
+            heartbeat_token: Self::start_heartbeat(config.interval, tx),
             writer,
         }
     }
 
-    /// Send a message over the WebSocket
-    pub async fn send(&mut self, response: Response) {
-        if let Err(e) = self.writer.send(StructuredResponse::data(response)).await {
+    /// Send a message over the WebSocket, returns false if it couldn't be sent
+    pub async fn send(&mut self, message: Message) -> bool {
+        if let Err(e) = self.writer.send(message).await {
             tracing::error!("{e}");
+            return false;
         }
+        true
     }
 
     /// Reset the ping handler thread
     pub fn on_ping(&mut self, tx: &Sender<Msg>) {
         self.auto_close_token.cancel();
-        self.auto_close_token = Self::start_auto_close(tx);
+        self.auto_close_token = Self::start_auto_close(self.auto_close, tx);
+    }
+
+    /// A pong is a reply to a client ping, and so also shows the server is alive
+    pub fn on_pong(&mut self, payload: &[u8], tx: &Sender<Msg>) {
+        self.heartbeat.pong(payload);
+        self.on_ping(tx);
+    }
+
+    /// Send the next client ping, returns false if too many pings have gone unanswered, and the socket should be closed
+    pub async fn ping(&mut self) -> bool {
+        let Some(payload) = self.heartbeat.ping() else {
+            tracing::warn!(
+                missed = self.heartbeat.missed(),
+                "{} pings without a pong, closing the connection",
+                self.heartbeat.missed()
+            );
+            return false;
+        };
+        if let Err(e) = self.writer.send(Message::Ping(payload.into())).await {
+            tracing::error!("{e}");
+        }
+        true
+    }
+
+    pub fn rtt(&self) -> Option<Rtt> {
+        self.heartbeat.rtt()
     }
 
     /// Close the socket
     pub async fn close(&mut self) {
         self.auto_close_token.cancel();
+        self.heartbeat_token.cancel();
         self.incoming_msg_token.cancel();
         tokio::time::timeout(std::time::Duration::from_secs(2), self.writer.close())
             .await
@@ -63,7 +103,8 @@ impl Socket {
         token
     }
 
-    /// Actually handle incoming WS messages
+    /// Actually handle incoming WS messages. However the stream ends, a close frame, an error, or EOF, the message handler
+    /// is told, so that the connection manager reconnects, even with both the heartbeat and auto close disabled
     async fn message_recv(mut reader: WSReader, tx: Sender<Msg>) {
         while let Ok(Some(x)) = reader.try_next().await {
             match x {
@@ -73,28 +114,146 @@ impl Socket {
                 Message::Ping(_) => {
                     tx.send(Msg::Ping).await.ok();
                 }
-                Message::Close(_) => {
-                    tx.send(Msg::WsClose).await.ok();
-                    break;
+                Message::Pong(payload) => {
+                    tx.send(Msg::Pong(payload.to_vec())).await.ok();
                 }
+                Message::Close(_) => break,
                 _ => tracing::info!("Unexpected WS message received"),
             }
         }
+        tx.send(Msg::WsClose).await.ok();
     }
 
-    /// Spawn autoclose method
-    fn start_auto_close(tx: &Sender<Msg>) -> CancellationToken {
+    /// Spawn autoclose method, unless disabled
+    fn start_auto_close(auto_close: Option<Duration>, tx: &Sender<Msg>) -> CancellationToken {
         let token = CancellationToken::new();
-        let (tx, t_token) = (C!(tx), C!(token));
-        tokio::spawn(async move {
-            t_token.run_until_cancelled(Self::sleep_then_send(tx)).await;
-        });
+        if let Some(auto_close) = auto_close {
+            let (tx, t_token) = (C!(tx), C!(token));
+            tokio::spawn(async move {
+                t_token
+                    .run_until_cancelled(Self::sleep_then_send(auto_close, tx))
+                    .await;
+            });
+        }
         token
     }
 
     /// Method run in the autoclose thread
-    async fn sleep_then_send(tx: Sender<Msg>) {
-        sleep!(40_000);
+    async fn sleep_then_send(auto_close: Duration, tx: Sender<Msg>) {
+        tokio::time::sleep(auto_close).await;
         tx.send(Msg::WsClose).await.ok();
     }
+
+    /// Spawn a thread to ask the message handler to send a ping on each interval, unless disabled
+    fn start_heartbeat(interval: Option<Duration>, tx: &Sender<Msg>) -> CancellationToken {
+        let token = CancellationToken::new();
+        if let Some(interval) = interval {
+            let (tx, t_token) = (C!(tx), C!(token));
+            tokio::spawn(async move {
+                t_token
+                    .run_until_cancelled(async move {
+                        let mut interval = tokio::time::interval(interval);
+                        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                        // The first tick is immediate
+                        interval.tick().await;
+                        loop {
+                            interval.tick().await;
+                            tx.send(Msg::Heartbeat).await.ok();
+                        }
+                    })
+                    .await;
+            });
+        }
+        token
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test socket_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A stand-in WS server, which keeps reading, so that pings are answered, until the client closes
+    async fn test_socket(config: HeartbeatConfig) -> (Socket, async_channel::Receiver<Msg>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            if let Ok((stream, _)) = listener.accept().await
+                && let Ok(mut socket) = tokio_tungstenite::accept_async(stream).await
+            {
+                while let Some(Ok(_)) = socket.next().await {}
+            }
+        });
+        let (stream, _) = tokio_tungstenite::connect_async(address).await.unwrap();
+        let (tx, rx) = async_channel::bounded(16);
+        (Socket::new(Box::new(stream), &tx, config), rx)
+    }
+
+    async fn next(rx: &async_channel::Receiver<Msg>) -> Msg {
+        tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn socket_heartbeat() {
+        let (mut socket, rx) = test_socket(HeartbeatConfig {
+            interval: Some(Duration::from_millis(50)),
+            missed: 2,
+            auto_close: None,
+        })
+        .await;
+        assert!(socket.rtt().is_none());
+
+        assert!(matches!(next(&rx).await, Msg::Heartbeat));
+        assert!(socket.ping().await);
+        let Msg::Pong(payload) = next(&rx).await else {
+            panic!("expected a pong");
+        };
+        assert_eq!(payload, 1u64.to_be_bytes());
+        let (tx, _) = async_channel::bounded(1);
+        socket.on_pong(&payload, &tx);
+        assert!(socket.rtt().is_some());
+        socket.close().await;
+    }
+
+    #[tokio::test]
+    async fn socket_connection_lost() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // Dropped without a close frame
+            drop(tokio_tungstenite::accept_async(stream).await.unwrap());
+        });
+        let (stream, _) = tokio_tungstenite::connect_async(address).await.unwrap();
+        let (tx, rx) = async_channel::bounded(16);
+        let mut socket = Socket::new(
+            Box::new(stream),
+            &tx,
+            HeartbeatConfig {
+                interval: None,
+                missed: 3,
+                auto_close: None,
+            },
+        );
+        assert!(matches!(next(&rx).await, Msg::WsClose));
+        socket.close().await;
+    }
+
+    #[tokio::test]
+    async fn socket_auto_close() {
+        let (mut socket, rx) = test_socket(HeartbeatConfig {
+            interval: None,
+            missed: 3,
+            auto_close: Some(Duration::from_millis(50)),
+        })
+        .await;
+        assert!(matches!(next(&rx).await, Msg::WsClose));
+        socket.close().await;
+    }
 }
@@ -0,0 +1,534 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::{CryptoProvider, WebPkiSupportedAlgorithms, ring},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+use sha2::{Digest, Sha256};
+use webpki::{EndEntityCert, KeyUsage, VerifiedPath};
+
+use crate::app_error::AppError;
+
+/// Optional prefix of each pin, as used by HPKP, and curl's `--pinnedpubkey`
+const PIN_PREFIX: &str = "sha256//";
+
+/// A SHA-256 hash of a DER encoded SubjectPublicKeyInfo
+pub type SpkiPin = [u8; 32];
+
+/// DER encode a SEQUENCE, as a trust anchor's SubjectPublicKeyInfo is stored without its outer tag and length
+fn der_sequence(contents: &[u8]) -> Vec<u8> {
+    let len = contents.len().to_be_bytes();
+    let len = &len[len.iter().take_while(|i| **i == 0).count()..];
+    let mut der = vec![0x30];
+    match len {
+        [] => der.push(0),
+        [short] if *short < 0x80 => der.push(*short),
+        long => {
+            der.push(0x80 | u8::try_from(long.len()).unwrap_or_default());
+            der.extend_from_slice(long);
+        }
+    }
+    der.extend_from_slice(contents);
+    der
+}
+
+/// Verifies the certificate chain as normal, and then checks that at least one certificate in the verified path, from the
+/// end entity to the trust anchor, matches a pin. Any other certificate sent by the server is ignored, as it needn't be
+/// related to the end entity
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    roots: Arc<RootCertStore>,
+    algorithms: WebPkiSupportedAlgorithms,
+    pins: Vec<SpkiPin>,
+}
+
+impl PinnedVerifier {
+    fn pinned(&self, spki: &[u8]) -> bool {
+        self.pins.contains(&Sha256::digest(spki).into())
+    }
+
+    fn path_pinned(&self, path: &VerifiedPath<'_>) -> bool {
+        self.pinned(&path.end_entity().subject_public_key_info())
+            || path
+                .intermediate_certificates()
+                .any(|i| self.pinned(&i.subject_public_key_info()))
+            || self.pinned(&der_sequence(&path.anchor().subject_public_key_info))
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        // Build the path again, as rustls doesn't expose it, any path which doesn't match a pin is rejected, so that
+        // another path is tried
+        let verify_path = |path: &VerifiedPath<'_>| {
+            if self.path_pinned(path) {
+                Ok(())
+            } else {
+                Err(webpki::Error::UnknownIssuer)
+            }
+        };
+        let pinned = EndEntityCert::try_from(end_entity).is_ok_and(|cert| {
+            cert.verify_for_usage(
+                self.algorithms.all,
+                &self.roots.roots,
+                intermediates,
+                now,
+                KeyUsage::server_auth(),
+                None,
+                Some(&verify_path),
+            )
+            .is_ok()
+        });
+        if pinned {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(
+                "no certificate matches WS_SPKI_PINS".to_owned(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Read every certificate in a PEM file
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, AppError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| AppError::Tls(format!("{}: {e}", path.display())))?;
+    if certs.is_empty() {
+        return Err(AppError::Tls(format!(
+            "{}: no certificates found",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+/// Extra TLS config, applied to both the token request and the WS connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// PEM file of CA certificates, trusted in addition to the native roots
+    pub ca_bundle: Option<PathBuf>,
+    /// PEM files of the client certificate chain, and its private key
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    /// If not empty, at least one certificate of the verified server chain, including the trust anchor, must match
+    pub spki_pins: Vec<SpkiPin>,
+}
+
+impl TlsConfig {
+    /// Parse a comma separated list of base64 encoded SHA-256 SPKI hashes, each optionally prefixed with `sha256//`
+    pub fn parse_pins(value: &str) -> Result<Vec<SpkiPin>, AppError> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|i| !i.is_empty())
+            .map(|i| {
+                STANDARD
+                    .decode(i.trim_start_matches(PIN_PREFIX))
+                    .ok()
+                    .and_then(|i| SpkiPin::try_from(i).ok())
+                    .ok_or_else(|| AppError::Tls(format!("invalid SPKI pin: '{i}'")))
+            })
+            .collect()
+    }
+
+    /// Build a rustls config, trusting the native roots, and anything extra that has been configured
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>, AppError> {
+        let provider = Arc::new(ring::default_provider());
+
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+        if let Some(path) = &self.ca_bundle {
+            for cert in read_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| AppError::Tls(format!("{}: {e}", path.display())))?;
+            }
+        }
+
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| AppError::Tls(e.to_string()))?;
+        let builder = if self.spki_pins.is_empty() {
+            builder.with_root_certificates(roots)
+        } else {
+            let roots = Arc::new(roots);
+            let inner = WebPkiServerVerifier::builder_with_provider(
+                Arc::clone(&roots),
+                Arc::<CryptoProvider>::clone(&provider),
+            )
+            .build()
+            .map_err(|e| AppError::Tls(e.to_string()))?;
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    inner,
+                    roots,
+                    algorithms: provider.signature_verification_algorithms,
+                    pins: self.spki_pins.clone(),
+                }))
+        };
+
+        let config = match &self.client_cert {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(
+                    read_certs(cert)?,
+                    PrivateKeyDer::from_pem_file(key)
+                        .map_err(|e| AppError::Tls(format!("{}: {e}", key.display())))?,
+                )
+                .map_err(|e| AppError::Tls(e.to_string()))?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/// cargo watch -q -c -w src/ -x 'test tls_ -- --nocapture'
+#[cfg(test)]
+#[expect(clippy::unwrap_used)]
+pub mod tests {
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair, PublicKeyData};
+    use rustls::{ServerConfig, server::WebPkiClientVerifier};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::*;
+    use crate::C;
+
+    /// The DER encoded SubjectPublicKeyInfo of a certificate, parsed by webpki
+    fn spki(cert: &[u8]) -> Option<Vec<u8>> {
+        let cert = CertificateDer::from(cert);
+        EndEntityCert::try_from(&cert)
+            .ok()
+            .map(|i| i.subject_public_key_info().to_vec())
+    }
+
+    /// A private CA, a server certificate for localhost, and a client certificate, each written to a temp directory
+    pub struct TestPki {
+        pub dir: PathBuf,
+        pub ca: CertificateDer<'static>,
+        pub ca_spki: Vec<u8>,
+        pub server: CertificateDer<'static>,
+        pub server_key: PrivateKeyDer<'static>,
+        pub server_spki: Vec<u8>,
+    }
+
+    impl TestPki {
+        pub fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "screen_control_test_tls_{name}_{}",
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+            let ca_spki = ca_key.subject_public_key_info();
+            let issuer = Issuer::new(ca_params, ca_key);
+
+            let server_key = KeyPair::generate().unwrap();
+            let server = CertificateParams::new(vec!["localhost".to_owned()])
+                .unwrap()
+                .signed_by(&server_key, &issuer)
+                .unwrap();
+
+            let client_key = KeyPair::generate().unwrap();
+            let client = CertificateParams::new(vec!["device".to_owned()])
+                .unwrap()
+                .signed_by(&client_key, &issuer)
+                .unwrap();
+
+            std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+            std::fs::write(dir.join("client.pem"), client.pem()).unwrap();
+            std::fs::write(dir.join("client.key"), client_key.serialize_pem()).unwrap();
+
+            Self {
+                ca: ca.der().clone(),
+                ca_spki,
+                server: server.der().clone(),
+                server_key: PrivateKeyDer::from_pem_slice(server_key.serialize_pem().as_bytes())
+                    .unwrap(),
+                server_spki: server_key.subject_public_key_info(),
+                dir,
+            }
+        }
+
+        /// A config which trusts the CA, and presents the client certificate
+        pub fn config(&self, pins: &[&[u8]]) -> TlsConfig {
+            TlsConfig {
+                ca_bundle: Some(self.dir.join("ca.pem")),
+                client_cert: Some((self.dir.join("client.pem"), self.dir.join("client.key"))),
+                spki_pins: pins.iter().map(|i| Sha256::digest(i).into()).collect(),
+            }
+        }
+
+        /// A TLS acceptor which requires a client certificate, signed by the CA, and only sends the server certificate
+        pub fn acceptor(&self) -> TlsAcceptor {
+            self.acceptor_with(&[])
+        }
+
+        /// As `acceptor`, also sending the extra certificates, whether or not they are part of the chain
+        pub fn acceptor_with(&self, extra: &[CertificateDer<'static>]) -> TlsAcceptor {
+            let provider = Arc::new(ring::default_provider());
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.clone()).unwrap();
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::clone(&provider))
+                    .build()
+                    .unwrap();
+            let config = ServerConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(
+                    std::iter::once(&self.server)
+                        .chain(extra)
+                        .cloned()
+                        .collect(),
+                    self.server_key.clone_key(),
+                )
+                .unwrap();
+            TlsAcceptor::from(Arc::new(config))
+        }
+
+        pub fn remove(&self) {
+            std::fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    /// A TLS server which writes "ok" to each client that completes the handshake
+    async fn tls_server(acceptor: TlsAcceptor) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    stream.write_all(b"ok").await.ok();
+                    stream.shutdown().await.ok();
+                }
+            }
+        });
+        port
+    }
+
+    /// Connect, and read the response, with the given client config
+    async fn handshake(config: Arc<ClientConfig>, port: u16) -> Result<Vec<u8>, std::io::Error> {
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut stream = TlsConnector::from(config)
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await?;
+        Ok(response)
+    }
+
+    #[test]
+    fn tls_spki() {
+        let pki = TestPki::new("spki");
+        assert_eq!(spki(&pki.server).unwrap(), pki.server_spki);
+        assert_eq!(spki(&pki.ca).unwrap(), pki.ca_spki);
+
+        // A trust anchor's SubjectPublicKeyInfo is stored without its outer SEQUENCE
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.clone()).unwrap();
+        assert_eq!(
+            der_sequence(&roots.roots[0].subject_public_key_info),
+            pki.ca_spki
+        );
+        assert_eq!(der_sequence(&[]), [0x30, 0]);
+        assert_eq!(der_sequence(&[7; 3]), [0x30, 3, 7, 7, 7]);
+        let long = der_sequence(&[7; 300]);
+        assert_eq!(long[..4], [0x30, 0x82, 1, 44]);
+        assert_eq!(long.len(), 304);
+
+        assert!(spki(&[]).is_none());
+        assert!(spki(&pki.server[..pki.server.len() / 2]).is_none());
+        assert!(spki(&[0x30, 0x82, 0xff]).is_none());
+        pki.remove();
+    }
+
+    #[test]
+    fn tls_parse_pins() {
+        let pin = STANDARD.encode([7; 32]);
+        let result = TlsConfig::parse_pins(&format!("{pin}, sha256//{pin},")).unwrap();
+        assert_eq!(result, [[7; 32], [7; 32]]);
+        assert!(TlsConfig::parse_pins("").unwrap().is_empty());
+
+        assert!(matches!(
+            TlsConfig::parse_pins("not base64!"),
+            Err(AppError::Tls(_))
+        ));
+        assert!(matches!(
+            TlsConfig::parse_pins(&STANDARD.encode([7; 20])),
+            Err(AppError::Tls(_))
+        ));
+    }
+
+    #[test]
+    fn tls_client_config_files() {
+        assert!(TlsConfig::default().client_config().is_ok());
+
+        let pki = TestPki::new("files");
+        assert!(pki.config(&[]).client_config().is_ok());
+
+        let config = TlsConfig {
+            ca_bundle: Some(pki.dir.join("missing.pem")),
+            ..TlsConfig::default()
+        };
+        assert!(
+            matches!(config.client_config(), Err(AppError::Tls(e)) if e.contains("missing.pem"))
+        );
+
+        // The key file contains no certificates
+        let config = TlsConfig {
+            ca_bundle: Some(pki.dir.join("client.key")),
+            ..TlsConfig::default()
+        };
+        assert!(
+            matches!(config.client_config(), Err(AppError::Tls(e)) if e.contains("no certificates"))
+        );
+
+        let config = TlsConfig {
+            client_cert: Some((pki.dir.join("client.pem"), pki.dir.join("ca.pem"))),
+            ..TlsConfig::default()
+        };
+        assert!(matches!(config.client_config(), Err(AppError::Tls(_))));
+        pki.remove();
+    }
+
+    #[tokio::test]
+    async fn tls_mutual() {
+        let pki = TestPki::new("mutual");
+        let port = tls_server(pki.acceptor()).await;
+
+        let config = pki.config(&[]).client_config().unwrap();
+        assert_eq!(handshake(config, port).await.unwrap(), b"ok");
+
+        // No client certificate
+        let config = TlsConfig {
+            client_cert: None,
+            ..pki.config(&[])
+        };
+        assert!(
+            handshake(config.client_config().unwrap(), port)
+                .await
+                .is_err()
+        );
+
+        // Private CA not trusted
+        let config = TlsConfig {
+            ca_bundle: None,
+            ..pki.config(&[])
+        };
+        assert!(
+            handshake(config.client_config().unwrap(), port)
+                .await
+                .is_err()
+        );
+        pki.remove();
+    }
+
+    #[tokio::test]
+    async fn tls_spki_pinning() {
+        let pki = TestPki::new("pinning");
+        let port = tls_server(pki.acceptor()).await;
+
+        // The CA isn't sent by the server, so is only matched as the trust anchor
+        for pins in [
+            vec![pki.server_spki.as_slice()],
+            vec![pki.ca_spki.as_slice()],
+            vec![b"other".as_slice(), pki.server_spki.as_slice()],
+        ] {
+            let config = pki.config(&pins).client_config().unwrap();
+            assert_eq!(handshake(config, port).await.unwrap(), b"ok");
+        }
+
+        let config = pki.config(&[b"other"]).client_config().unwrap();
+        let result = handshake(config, port).await;
+        assert!(result.unwrap_err().to_string().contains("WS_SPKI_PINS"));
+        pki.remove();
+    }
+
+    #[tokio::test]
+    async fn tls_spki_pinning_unused_certificate() {
+        let pki = TestPki::new("pinning_unused");
+        let other = TestPki::new("pinning_unused_other");
+
+        // The other server's chain is valid, as both CAs are trusted, but the pinned certificates are unrelated extras
+        let port = tls_server(other.acceptor_with(&[C!(pki.server), C!(pki.ca)])).await;
+        let bundle = pki.dir.join("both.pem");
+        std::fs::write(
+            &bundle,
+            [
+                std::fs::read(pki.dir.join("ca.pem")).unwrap(),
+                std::fs::read(other.dir.join("ca.pem")).unwrap(),
+            ]
+            .concat(),
+        )
+        .unwrap();
+        let config = |pins: &[&[u8]]| TlsConfig {
+            ca_bundle: Some(C!(bundle)),
+            ..other.config(pins)
+        };
+
+        for pins in [pki.server_spki.as_slice(), pki.ca_spki.as_slice()] {
+            let result = handshake(config(&[pins]).client_config().unwrap(), port).await;
+            assert!(result.unwrap_err().to_string().contains("WS_SPKI_PINS"));
+        }
+
+        let config = config(&[other.ca_spki.as_slice()]).client_config().unwrap();
+        assert_eq!(handshake(config, port).await.unwrap(), b"ok");
+        pki.remove();
+        other.remove();
+    }
+}
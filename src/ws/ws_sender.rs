@@ -2,9 +2,12 @@ use async_channel::Sender;
 use std::{process, time::Instant};
 
 use crate::C;
-use crate::message_handler::Msg;
+use crate::message_handler::{Msg, Source};
 use crate::sysinfo::SysInfo;
-use crate::ws_messages::{MessageValues, ParsedMessage, PiStatus, Response};
+use crate::ws_messages::{
+    ConnectionStatus, MessageValues, ParsedMessage, PendingRevert, PiStatus, Response, Rtt,
+    ScreenBody,
+};
 use crate::{app_env::AppEnv, ws_messages::to_struct};
 
 #[derive(Debug, Clone)]
@@ -33,17 +36,29 @@ impl WSSender {
         if let Some(data) = to_struct(&message) {
             match data {
                 MessageValues::Invalid(error) => tracing::error!("invalid::{error:?}"),
-                MessageValues::Valid(message) => match message {
-                    ParsedMessage::ScreenOff => {
-                        self.tx.send(Msg::ScreenOff).await.ok();
-                    }
-                    ParsedMessage::Status => {
-                        self.tx.send(Msg::Status).await.ok();
-                    }
-                    ParsedMessage::ScreenOn => {
-                        self.tx.send(Msg::ScreenOn).await.ok();
-                    }
-                },
+                MessageValues::Valid(message, unique) => {
+                    tracing::info!(event = message.name(), unique, "ws message received");
+                    self.on_message(message).await;
+                }
+            }
+        }
+    }
+
+    async fn on_message(&self, message: ParsedMessage) {
+        match message {
+            ParsedMessage::ScreenOff(body) => {
+                let duration = ScreenBody::duration(body);
+                self.tx
+                    .send(Msg::ScreenOff(duration, Source::Ws))
+                    .await
+                    .ok();
+            }
+            ParsedMessage::Status => {
+                self.tx.send(Msg::Status).await.ok();
+            }
+            ParsedMessage::ScreenOn(body) => {
+                let duration = ScreenBody::duration(body);
+                self.tx.send(Msg::ScreenOn(duration, Source::Ws)).await.ok();
             }
         }
     }
@@ -58,10 +73,32 @@ impl WSSender {
         }
     }
 
-    /// Generate, and send, pi information
-    pub async fn send_status(&self) {
+    /// Generate pi information
+    pub async fn status(
+        &self,
+        revert: Option<PendingRevert>,
+        connection: Option<ConnectionStatus>,
+        rtt: Option<Rtt>,
+    ) -> PiStatus {
         let sys_info = SysInfo::new(&self.app_envs).await;
-        let pi_info = PiStatus::new(sys_info, self.connected_instant.elapsed().as_secs());
-        self.send_ws_response(Response::Status(pi_info)).await;
+        PiStatus::new(
+            sys_info,
+            self.connected_instant.elapsed().as_secs(),
+            revert,
+            connection,
+            rtt,
+        )
+    }
+
+    /// Generate, and send, pi information
+    pub async fn send_status(
+        &self,
+        revert: Option<PendingRevert>,
+        connection: Option<ConnectionStatus>,
+        rtt: Option<Rtt>,
+    ) {
+        let pi_info = self.status(revert, connection, rtt).await;
+        self.send_ws_response(Response::Status(Box::new(pi_info)))
+            .await;
     }
 }
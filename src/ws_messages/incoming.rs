@@ -1,8 +1,11 @@
+use std::{fmt, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
 pub enum MessageValues {
-    Valid(ParsedMessage),
+    /// The message, and its `unique` request id
+    Valid(ParsedMessage, String),
     Invalid(ErrorData),
 }
 
@@ -13,6 +16,14 @@ pub enum ScreenStatus {
 }
 
 impl ScreenStatus {
+    /// The opposite status, used when toggling
+    pub const fn toggle(&self) -> Self {
+        match self {
+            Self::Off => Self::On,
+            Self::On => Self::Off,
+        }
+    }
+
     /// Used in the toggle screen command
     pub const fn get_arg_value(&self) -> &'static str {
         match self {
@@ -22,22 +33,75 @@ impl ScreenStatus {
     }
 }
 
+impl fmt::Display for ScreenStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::On => write!(f, "on"),
+            Self::Off => write!(f, "off"),
+        }
+    }
+}
+
+/// Optional body of the screen_on & screen_off messages, revert to the previous screen status after `duration` seconds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenBody {
+    pub duration: u64,
+}
+
+impl ScreenBody {
+    /// Convert an optional message body into an optional non-zero duration
+    pub fn duration(body: Option<Self>) -> Option<Duration> {
+        body.and_then(|i| (i.duration > 0).then(|| Duration::from_secs(i.duration)))
+    }
+}
+
+impl From<Duration> for ScreenBody {
+    fn from(duration: Duration) -> Self {
+        Self {
+            duration: duration.as_secs(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case", tag = "name", content = "body")]
 pub enum ParsedMessage {
     Status,
-    ScreenOn,
-    ScreenOff,
+    ScreenOn(Option<ScreenBody>),
+    ScreenOff(Option<ScreenBody>),
+}
+
+impl ParsedMessage {
+    /// The message name, as used in the JSON, and as the event field in the logs
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Status => "status",
+            Self::ScreenOn(_) => "screen_on",
+            Self::ScreenOff(_) => "screen_off",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
-struct StructuredMessage {
+pub struct StructuredMessage {
     data: Option<ParsedMessage>,
     error: Option<ErrorData>,
     unique: String,
 }
 
+impl StructuredMessage {
+    /// Serialize a message, used when sending commands over the control socket
+    pub fn to_json(data: ParsedMessage, unique: &str) -> String {
+        let x = Self {
+            data: Some(data),
+            error: None,
+            unique: unique.to_owned(),
+        };
+        serde_json::to_string(&x).unwrap_or_default()
+    }
+}
+
 // TODO - this is, at the moment, pointless
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case", tag = "error", content = "message")]
@@ -52,7 +116,7 @@ pub fn to_struct(input: &str) -> Option<MessageValues> {
             return Some(MessageValues::Invalid(message));
         }
         if let Some(message) = data.data {
-            return Some(MessageValues::Valid(message));
+            return Some(MessageValues::Valid(message, data.unique));
         }
         None
     } else {
@@ -78,7 +142,7 @@ mod tests {
         let result = to_struct(json);
         assert!(result.is_some());
         match result.unwrap() {
-            MessageValues::Valid(_) => (),
+            MessageValues::Valid(..) => (),
             MessageValues::Invalid(_) => unreachable!("this indicates the test has failed"),
         }
     }
@@ -106,6 +170,15 @@ mod tests {
 
         // valid message
         test_is_some(r#"{ "data": { "name": "status" }, "unique": "random_string"}"#);
+
+        // unique is returned with the message
+        match to_struct(r#"{ "data": { "name": "status" }, "unique": "random_string"}"#) {
+            Some(MessageValues::Valid(message, unique)) => {
+                assert_eq!(message.name(), "status");
+                assert_eq!(unique, "random_string");
+            }
+            _ => unreachable!("this indicates the test has failed"),
+        }
     }
 
     #[test]
@@ -116,4 +189,38 @@ mod tests {
         // valid screen off
         test_is_some(r#"{ "data": { "name": "screen_off" }, "unique":"true"}"#);
     }
+
+    #[test]
+    fn message_incoming_parse_screen_duration() {
+        let get_duration = |json: &str| match to_struct(json).unwrap() {
+            MessageValues::Valid(
+                ParsedMessage::ScreenOn(body) | ParsedMessage::ScreenOff(body),
+                _,
+            ) => ScreenBody::duration(body),
+            _ => unreachable!("this indicates the test has failed"),
+        };
+
+        let result = get_duration(
+            r#"{ "data": { "name": "screen_on", "body": { "duration": 1200 } }, "unique":"true"}"#,
+        );
+        assert_eq!(result, Some(Duration::from_secs(1200)));
+
+        let result = get_duration(
+            r#"{ "data": { "name": "screen_off", "body": { "duration": 60 } }, "unique":"true"}"#,
+        );
+        assert_eq!(result, Some(Duration::from_secs(60)));
+
+        let result = get_duration(
+            r#"{ "data": { "name": "screen_off", "body": { "duration": 0 } }, "unique":"true"}"#,
+        );
+        assert!(result.is_none());
+
+        let result = get_duration(r#"{ "data": { "name": "screen_on" }, "unique":"true"}"#);
+        assert!(result.is_none());
+
+        // invalid duration
+        test_is_none(
+            r#"{ "data": { "name": "screen_on", "body": { "duration": "20m" } }, "unique":"true"}"#,
+        );
+    }
 }
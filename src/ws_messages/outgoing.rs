@@ -1,4 +1,6 @@
-use jiff::Zoned;
+use std::fmt;
+
+use jiff::{SignedDuration, Timestamp, Zoned};
 use serde::{Deserialize, Serialize};
 use tokio_tungstenite::tungstenite::Message;
 
@@ -6,10 +8,119 @@ use crate::sysinfo::SysInfo;
 
 use super::ScreenStatus;
 
+/// A timed screen on/off, which will be reverted to `screen_status` at the unix timestamp `at`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PendingRevert {
+    pub screen_status: ScreenStatus,
+    pub at: i64,
+}
+
+/// A unix timestamp, in seconds, as a human readable UTC date time
+fn format_at(at: i64) -> String {
+    Timestamp::from_second(at).map_or_else(
+        |_| "unknown".to_owned(),
+        |i| i.strftime("%Y-%m-%d %H:%M:%S UTC").to_string(),
+    )
+}
+
+impl fmt::Display for PendingRevert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.screen_status, format_at(self.at))
+    }
+}
+
+/// State of the WS connection, as managed by the connection manager task
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+    Backoff,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            Self::Disconnected => "disconnected",
+            Self::Connecting => "connecting",
+            Self::Connected => "connected",
+            Self::Backoff => "backoff",
+        };
+        write!(f, "{state}")
+    }
+}
+
+/// The most recent failed connection attempt, `kind` is a stable name for the error, and `at` a unix timestamp
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionError {
+    pub kind: String,
+    pub message: String,
+    pub at: i64,
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) at {}",
+            self.message,
+            self.kind,
+            format_at(self.at)
+        )
+    }
+}
+
+/// WS connection state, the endpoint in use, the backoff attempt, and the most recent backoff delay. Along with
+/// diagnostics, the number of reconnects since the daemon started, the consecutive failed attempts, the most recent
+/// error, and the unix timestamp of the most recent successful connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    pub endpoint: Option<String>,
+    pub attempt: u32,
+    pub delay_ms: Option<u64>,
+    pub reconnects: u64,
+    pub failures: u32,
+    pub last_error: Option<ConnectionError>,
+    pub last_connected_at: Option<i64>,
+}
+
+impl fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.state)?;
+        if let Some(endpoint) = &self.endpoint {
+            write!(f, " ({endpoint})")?;
+        }
+        write!(f, ", attempt {}", self.attempt)?;
+        if let Some(delay_ms) = self.delay_ms {
+            write!(f, ", last backoff {delay_ms}ms")?;
+        }
+        Ok(())
+    }
+}
+
+/// Round trip time of the client pings, the most recent, and the average of the last few
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rtt {
+    pub latest_ms: u64,
+    pub average_ms: u64,
+}
+
+impl fmt::Display for Rtt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}ms, average {}ms", self.latest_ms, self.average_ms)
+    }
+}
+
 /// Basic pi info
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PiStatus {
+    pub connection: Option<ConnectionStatus>,
     pub ip_address: String,
+    pub revert: Option<PendingRevert>,
+    pub rtt: Option<Rtt>,
     pub screen_status: Option<ScreenStatus>,
     pub time_off: (i8, i8),
     pub time_on: (i8, i8),
@@ -21,10 +132,19 @@ pub struct PiStatus {
 }
 /// Combined pi into and current set alarms
 impl PiStatus {
-    pub fn new(sysinfo: SysInfo, uptime_ws: u64) -> Self {
+    pub fn new(
+        sysinfo: SysInfo,
+        uptime_ws: u64,
+        revert: Option<PendingRevert>,
+        connection: Option<ConnectionStatus>,
+        rtt: Option<Rtt>,
+    ) -> Self {
         let zone = Zoned::now();
         Self {
+            connection,
             ip_address: sysinfo.ip_address,
+            revert,
+            rtt,
             screen_status: sysinfo.screen_status,
             time_off: sysinfo.time_off,
             time_on: sysinfo.time_on,
@@ -36,11 +156,63 @@ impl PiStatus {
         }
     }
 }
+
+/// Human readable status, as used by the status cli command
+impl fmt::Display for PiStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let duration =
+            |secs: u64| SignedDuration::from_secs(i64::try_from(secs).unwrap_or(i64::MAX));
+        let screen = self
+            .screen_status
+            .as_ref()
+            .map_or_else(|| "unknown".to_owned(), ToString::to_string);
+        writeln!(f, "screen:     {screen}")?;
+        writeln!(
+            f,
+            "schedule:   on {:02}:{:02}, off {:02}:{:02} ({})",
+            self.time_on.0, self.time_on.1, self.time_off.0, self.time_off.1, self.timezone
+        )?;
+        if let Some(revert) = &self.revert {
+            writeln!(f, "revert:     {revert}")?;
+        }
+        writeln!(f, "ip address: {}", self.ip_address)?;
+        writeln!(f, "uptime:     {:#}", duration(self.uptime as u64))?;
+        writeln!(f, "app uptime: {:#}", duration(self.uptime_app))?;
+        writeln!(f, "ws uptime:  {:#}", duration(self.uptime_ws))?;
+        if let Some(connection) = &self.connection {
+            writeln!(f, "connection: {connection}")?;
+            writeln!(
+                f,
+                "reconnects: {}, consecutive failures {}",
+                connection.reconnects, connection.failures
+            )?;
+            if let Some(at) = connection.last_connected_at {
+                writeln!(f, "connected:  {}", format_at(at))?;
+            }
+            if let Some(error) = &connection.last_error {
+                writeln!(f, "last error: {error}")?;
+            }
+        }
+        if let Some(rtt) = &self.rtt {
+            writeln!(f, "rtt:        {rtt}")?;
+        }
+        write!(f, "version:    {}", self.version)
+    }
+}
+/// A successful screen change, and what requested it, sent as it happens, so that the WS server is told of every change,
+/// even those whilst disconnected, rather than only the latest status
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScreenEvent {
+    pub screen_status: ScreenStatus,
+    pub source: String,
+}
+
 /// Responses, either sent as is, or nested in StructuredResponse below
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case", tag = "name", content = "data")]
 pub enum Response {
-    Status(PiStatus),
+    Status(Box<PiStatus>),
+    ScreenChange(ScreenEvent),
     Error(String),
 }
 
@@ -51,24 +223,175 @@ pub struct StructuredResponse {
     data: Option<Response>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<Response>,
+    /// Unix timestamp, in ms, of when a response was queued in the outbox whilst disconnected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queued_at_ms: Option<i64>,
 }
 
 impl StructuredResponse {
-    /// Convert a ResponseMessage into a Tokio message of StructureResponse
-    pub fn data(data: Response) -> Message {
+    /// Serialize a Response as the data field of a StructureResponse
+    pub fn data_json(data: Response) -> String {
         let x = Self {
             data: Some(data),
             error: None,
+            queued_at_ms: None,
         };
-        Message::Text(serde_json::to_string(&x).unwrap_or_default().into())
+        serde_json::to_string(&x).unwrap_or_default()
     }
 
-    /// Convert a ErrorResponse into a Tokio message of StructureResponse
-    pub fn _error(data: Response) -> Message {
+    /// Serialize a Response as the error field of a StructureResponse
+    pub fn error_json(data: Response) -> String {
         let x = Self {
             error: Some(data),
             data: None,
+            queued_at_ms: None,
+        };
+        serde_json::to_string(&x).unwrap_or_default()
+    }
+
+    /// Convert a ResponseMessage into a Tokio message of StructureResponse
+    pub fn data(data: Response) -> Message {
+        Message::Text(Self::data_json(data).into())
+    }
+
+    /// Convert a ResponseMessage, replayed from the outbox, into a Tokio message of StructureResponse
+    pub fn queued(data: Response, queued_at_ms: i64) -> Message {
+        let x = Self {
+            data: Some(data),
+            error: None,
+            queued_at_ms: Some(queued_at_ms),
         };
         Message::Text(serde_json::to_string(&x).unwrap_or_default().into())
     }
+
+    /// Extract the Response, from either the data or the error field
+    pub fn into_response(self) -> Option<Response> {
+        self.data.or(self.error)
+    }
+}
+
+/// message_outgoing
+///
+/// cargo watch -q -c -w src/ -x 'test message_outgoing -- --nocapture'
+#[cfg(test)]
+mod tests {
+    use crate::S;
+
+    use super::*;
+
+    fn test_status() -> PiStatus {
+        PiStatus {
+            connection: None,
+            ip_address: S!("192.168.1.2"),
+            revert: None,
+            rtt: None,
+            screen_status: Some(ScreenStatus::On),
+            time_off: (21, 0),
+            time_on: (8, 5),
+            timezone: S!("Europe/London"),
+            uptime_app: 65,
+            uptime_ws: 0,
+            uptime: 93_784,
+            version: S!("0.2.0"),
+        }
+    }
+
+    #[test]
+    fn message_outgoing_status_display() {
+        let result = test_status().to_string();
+        assert_eq!(
+            result,
+            "screen:     on
+schedule:   on 08:05, off 21:00 (Europe/London)
+ip address: 192.168.1.2
+uptime:     26h 3m 4s
+app uptime: 1m 5s
+ws uptime:  0s
+version:    0.2.0"
+        );
+
+        let mut status = test_status();
+        status.revert = Some(PendingRevert {
+            screen_status: ScreenStatus::Off,
+            at: 1_750_000_000,
+        });
+        assert!(
+            status
+                .to_string()
+                .contains("\nrevert:     off at 2025-06-15 15:06:40 UTC\n")
+        );
+
+        let mut status = test_status();
+        status.connection = Some(ConnectionStatus {
+            state: ConnectionState::Connected,
+            endpoint: Some(S!("wss://standby.example.com")),
+            attempt: 0,
+            delay_ms: None,
+            ..ConnectionStatus::default()
+        });
+        assert!(
+            status
+                .to_string()
+                .contains("\nconnection: connected (wss://standby.example.com), attempt 0\n")
+        );
+        status.connection = Some(ConnectionStatus {
+            state: ConnectionState::Backoff,
+            endpoint: None,
+            attempt: 3,
+            delay_ms: Some(2_750),
+            reconnects: 4,
+            failures: 2,
+            last_error: Some(ConnectionError {
+                kind: S!("connect_timeout"),
+                message: S!("TCP/TLS connect timed out after 10000ms"),
+                at: 1_750_000_100,
+            }),
+            last_connected_at: Some(1_750_000_000),
+        });
+        assert!(status.to_string().contains(
+            "\nconnection: backoff, attempt 3, last backoff 2750ms
+reconnects: 4, consecutive failures 2
+connected:  2025-06-15 15:06:40 UTC
+last error: TCP/TLS connect timed out after 10000ms (connect_timeout) at 2025-06-15 15:08:20 UTC\n"
+        ));
+
+        let mut status = test_status();
+        status.rtt = Some(Rtt {
+            latest_ms: 42,
+            average_ms: 37,
+        });
+        assert!(
+            status
+                .to_string()
+                .contains("\nws uptime:  0s\nrtt:        42ms, average 37ms\n")
+        );
+    }
+
+    #[test]
+    fn message_outgoing_queued() {
+        let result = StructuredResponse::data_json(Response::Error(S!("oops")));
+        assert_eq!(result, r#"{"data":{"name":"error","data":"oops"}}"#);
+
+        let Message::Text(result) =
+            StructuredResponse::queued(Response::Error(S!("oops")), 1_700_000_000_000)
+        else {
+            unreachable!()
+        };
+        assert_eq!(
+            result.as_str(),
+            r#"{"data":{"name":"error","data":"oops"},"queued_at_ms":1700000000000}"#
+        );
+    }
+
+    #[test]
+    fn message_outgoing_screen_change() {
+        let result = StructuredResponse::data_json(Response::ScreenChange(ScreenEvent {
+            screen_status: ScreenStatus::Off,
+            source: S!("schedule"),
+        }));
+        assert_eq!(
+            result,
+            r#"{"data":{"name":"screen_change","data":{"screen_status":"Off","source":"schedule"}}}"#
+        );
+    }
 }